@@ -0,0 +1,70 @@
+use similar::{ChangeTag, TextDiff};
+
+/// Render a unified diff from `old` to `new`, or `None` if they are identical.
+///
+/// With `color`, removed lines are red, added lines green and hunk headers cyan.
+pub fn unified_diff(old: &str, new: &str, path: &str, color: bool) -> Option<String> {
+    if old == new {
+        return None;
+    }
+
+    let diff = TextDiff::from_lines(old, new);
+    let mut out = String::new();
+
+    push_line(&mut out, &format!("--- a/{}", path), "1", color);
+    push_line(&mut out, &format!("+++ b/{}", path), "1", color);
+
+    for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+        push_line(&mut out, &hunk.header().to_string(), "36", color);
+
+        for change in hunk.iter_changes() {
+            let (sign, style) = match change.tag() {
+                ChangeTag::Delete => ('-', "31"),
+                ChangeTag::Insert => ('+', "32"),
+                ChangeTag::Equal => (' ', ""),
+            };
+            let text = change.value().trim_end_matches(['\r', '\n']);
+            push_line(&mut out, &format!("{}{}", sign, text), style, color);
+        }
+    }
+
+    Some(out)
+}
+
+fn push_line(out: &mut String, line: &str, style: &str, color: bool) {
+    if color && !style.is_empty() {
+        out.push_str(&format!("\x1b[{}m{}\x1b[0m\n", style, line));
+    } else {
+        out.push_str(line);
+        out.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_added_and_removed_lines() {
+        let old = "int a;\nint b;\nmain { }\n";
+        let new = "int a;\nint c;\nmain { }\nint d;\n";
+
+        let diff = unified_diff(old, new, "build/build.gpc", false).unwrap();
+        let lines: Vec<&str> = diff.lines().collect();
+
+        assert_eq!(lines[0], "--- a/build/build.gpc");
+        assert!(lines.contains(&"-int b;"));
+        assert!(lines.contains(&"+int c;"));
+        assert!(lines.contains(&"+int d;"));
+        assert!(lines.contains(&" int a;"));
+        assert!(!lines.contains(&"-int a;"));
+
+        let colored = unified_diff(old, new, "build/build.gpc", true).unwrap();
+        assert!(colored.contains("\x1b[31m-int b;\x1b[0m"));
+    }
+
+    #[test]
+    fn test_identical_output_has_no_diff() {
+        assert!(unified_diff("int a;\n", "int a;\n", "out.gpc", false).is_none());
+    }
+}
@@ -1,65 +1,1008 @@
-use clap::Args;
+use crate::error::{ErsaError, Result};
+use clap::{Args, ValueEnum};
 use std::fs;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
 
+pub mod diff;
 pub mod preprocess;
+pub mod target;
+pub mod watch;
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Clone)]
 pub struct BuildArgs {
+    /// Input file; `-` reads stdin, resolving imports from the current
+    /// directory and printing the result unless `-o` is given
     #[arg(long, short = 'f')]
     file: Option<String>,
 
+    /// Build the module at this import path (e.g. `lib/util`) instead of a file
+    #[arg(long, value_name = "IMPORT_PATH", conflicts_with = "file")]
+    entry: Option<String>,
+
     #[arg(long, short = 'o')]
     output: Option<String>,
+
+    /// Define a macro, e.g. `-D DEBUG` or `-D MAX=100`
+    #[arg(long = "define", short = 'D', value_name = "NAME[=VALUE]")]
+    defines: Vec<String>,
+
+    /// Load defines from a file, one `NAME[=VALUE]` per line (can be repeated)
+    #[arg(long, value_name = "FILE")]
+    define_from: Vec<String>,
+
+    /// Replace each macro definition with a `// macro NAME(params)` comment
+    #[arg(long)]
+    keep_macro_comments: bool,
+
+    /// Abort the build if preprocessing takes longer than this many seconds
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// Report every failed import instead of stopping at the first
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Also search this directory for imports (can be repeated)
+    #[arg(long = "include", short = 'I', value_name = "DIR")]
+    include_dirs: Vec<String>,
+
+    /// Warn when a macro's name is used without `!`, which is likely a typo
+    #[arg(long)]
+    strict_macros: bool,
+
+    /// Warn about macros that are defined but never used, including those
+    /// from imported files
+    #[arg(long)]
+    warn_unused_macros: bool,
+
+    /// Substitute macro parameters one at a time, so later parameters are also
+    /// replaced inside earlier arguments: `f(b, 1)!` of `define! f(a, b) { a + b }`
+    /// gives `1 + 1`. By default all parameters are substituted at once,
+    /// giving `b + 1`
+    #[arg(long)]
+    sequential_params: bool,
+
+    /// Print the path of every inlined file to stderr
+    #[arg(long)]
+    print_imports: bool,
+
+    /// Print each macro expansion to stderr: the macro, its arguments and
+    /// the start of the text it expanded to
+    #[arg(long)]
+    trace_macros: bool,
+
+    /// Reject macro definitions whose body is longer than this many bytes
+    #[arg(long, value_name = "BYTES")]
+    max_macro_body: Option<usize>,
+
+    /// Inline a file again each time it is imported, instead of only the
+    /// first time; imports marked `@once` are still inlined only once
+    #[arg(long)]
+    reinline_imports: bool,
+
+    /// Abort the build once more than this many files have been inlined
+    #[arg(long, value_name = "N", default_value_t = 10_000)]
+    max_imports: usize,
+
+    /// What to write: the built source, or a Makefile-style depfile of the
+    /// source files the build reads. With `deps`, `-o` names the depfile
+    /// (stdout if omitted) and the rule target is the configured output.
+    #[arg(long, value_enum, default_value_t = Emit::Build)]
+    emit: Emit,
+
+    /// Layout of the output: verbatim, reindented by brace depth, or with
+    /// indentation and blank lines removed
+    #[arg(long, value_enum, default_value_t = preprocess::format::Format::None)]
+    format: preprocess::format::Format,
+
+    /// Fail if the build logs more than this many warnings
+    #[arg(long, value_name = "N")]
+    fail_on_warning_count: Option<usize>,
+
+    /// Show a diff against the previous build output
+    #[arg(long)]
+    diff: bool,
+
+    /// Fail if the output doesn't fit this device's limits (e.g. `zen`,
+    /// `titan-two`, or a `[targets.<name>]` profile from the config)
+    #[arg(long, value_name = "DEVICE")]
+    target: Option<String>,
+
+    /// Rebuild whenever the entry file or one of its imports changes
+    #[arg(long)]
+    watch: bool,
+
+    /// Clear the terminal before each rebuild in watch mode
+    #[arg(long, requires = "watch")]
+    clear: bool,
+
+    /// Leave imports in place
+    #[arg(long)]
+    no_imports: bool,
+
+    /// Leave macro definitions and calls, and `//!` build comments, in place
+    #[arg(long)]
+    no_macros: bool,
+
+    /// Skip constant folding
+    #[arg(long)]
+    no_optimize: bool,
+
+    /// Run only this pass (can be repeated)
+    #[arg(
+        long,
+        value_enum,
+        value_name = "PASS",
+        conflicts_with_all = ["no_imports", "no_macros", "no_optimize"]
+    )]
+    only: Vec<preprocess::Pass>,
 }
 
-pub async fn run(args: BuildArgs) -> Result<(), String> {
-    // Determine input file
-    let input_path = if let Some(file) = args.file {
-        PathBuf::from(file)
-    } else {
-        // Default to main.gpc in current directory
-        let cwd = std::env::current_dir()
-            .map_err(|e| format!("Failed to get current directory: {}", e))?;
-        cwd.join("main.gpc")
-    };
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Emit {
+    Build,
+    Deps,
+}
 
-    // Check if input file exists
-    if !input_path.exists() {
-        return Err(format!("Input file not found: {}", input_path.display()));
+impl BuildArgs {
+    fn passes(&self) -> preprocess::Passes {
+        if !self.only.is_empty() {
+            return preprocess::Passes::only(&self.only);
+        }
+        preprocess::Passes {
+            imports: !self.no_imports,
+            macros: !self.no_macros,
+            optimize: !self.no_optimize,
+        }
+    }
+}
+
+pub async fn run(args: BuildArgs) -> Result<()> {
+    if args.file.as_deref() == Some("-") {
+        if let Some(flag) = unsupported_with_stdin(&args) {
+            return Err(ErsaError::Other(format!(
+                "{} needs an entry file, not stdin",
+                flag
+            )));
+        }
+        return build_stdin(args);
+    }
+    if args.emit == Emit::Deps {
+        if let Some(flag) = unsupported_with_deps(&args) {
+            return Err(ErsaError::Other(format!(
+                "{} can't be used with --emit deps",
+                flag
+            )));
+        }
+        return emit_deps(args);
+    }
+    if args.watch {
+        let clear = args.clear;
+        watch::Watch::new(Rebuilder { args }, clear).run(Duration::from_millis(500));
+    }
+    build(args).map(|_| ())
+}
+
+/// Runs the real build for `--watch`
+struct Rebuilder {
+    args: BuildArgs,
+}
+
+impl watch::Rebuild for Rebuilder {
+    fn rebuild(&mut self) -> Vec<PathBuf> {
+        if let Err(e) = build(self.args.clone()) {
+            crate::log::error(&e.to_string());
+        }
+
+        let mut files = match input_path(&self.args) {
+            Ok((input_path, include_dirs)) => {
+                match preprocess_options(
+                    &self.args,
+                    preprocess::Language::from_path(&input_path),
+                    include_dirs,
+                ) {
+                    Ok(options) => source_files(&input_path, &options),
+                    Err(_) => vec![input_path],
+                }
+            }
+            Err(_) => Vec::new(),
+        };
+        // Watched even when they fail to parse, so fixing them rebuilds
+        files.extend(define_files(&self.args));
+        crate::log::info("Watching for changes...");
+        files
+    }
+
+    fn clear(&mut self) {
+        watch::clear_screen();
     }
+}
+
+/// The entry file to build and the include directories, both resolved
+/// against the current directory
+fn input_path(args: &BuildArgs) -> Result<(PathBuf, Vec<PathBuf>)> {
+    let cwd = std::env::current_dir().map_err(ErsaError::io("Failed to get current directory"))?;
+    let include_dirs: Vec<PathBuf> = args.include_dirs.iter().map(|d| cwd.join(d)).collect();
+    let input_path = entry_path(
+        &cwd,
+        args.entry.as_deref(),
+        args.file.clone(),
+        &include_dirs,
+    )?;
+    Ok((input_path, include_dirs))
+}
+
+/// Result of building a file in memory
+#[derive(Debug, Clone, Default)]
+pub struct BuildOutput {
+    /// The fully preprocessed source
+    pub code: String,
+    /// Non-fatal problems found while building
+    pub warnings: Vec<String>,
+}
+
+/// Build the input file and return the path of the written output
+pub fn build(args: BuildArgs) -> Result<PathBuf> {
+    build_with_strict(args, crate::cli::is_strict(), crate::config::get())
+}
+
+/// [`build`] with `config`, failing before anything is written if `strict`
+/// and the build has warnings
+fn build_with_strict(
+    args: BuildArgs,
+    strict: bool,
+    config: &crate::config::Config,
+) -> Result<PathBuf> {
+    let cwd = std::env::current_dir().map_err(ErsaError::io("Failed to get current directory"))?;
+    let (input_path, include_dirs) = input_path(&args)?;
 
     crate::log::info(&format!("Building file: {}", input_path.display()));
 
-    // Read input file
-    let code =
-        fs::read_to_string(&input_path).map_err(|e| format!("Failed to read input file: {}", e))?;
+    let options = preprocess_options(
+        &args,
+        preprocess::Language::from_path(&input_path),
+        include_dirs,
+    )?;
+    let output = match args.timeout {
+        Some(secs) => build_file_with_timeout(&input_path, &options, Duration::from_secs(secs))?,
+        None => build_file(&input_path, &options)?,
+    };
 
-    // Preprocess the code
-    let base_path = input_path.parent().unwrap_or(std::path::Path::new("."));
-    let preprocessed = preprocess::preprocess(&code, base_path)?;
+    for warning in &output.warnings {
+        crate::log::warn(warning);
+    }
+    check_strict(strict, output.warnings.len())?;
+    check_warning_budget(args.fail_on_warning_count, output.warnings.len())?;
+    check_target(args.target.as_deref(), config, &output.code)?;
 
     // Determine output path
-    let output_path = if let Some(output) = args.output {
-        PathBuf::from(output)
-    } else {
-        // Default to pwd/build/build.gpc
-        let cwd = std::env::current_dir()
-            .map_err(|e| format!("Failed to get current directory: {}", e))?;
-        cwd.join("build").join("build.gpc")
-    };
+    let output_file = config.build_output(args.output)?;
+    let output_path = cwd.join(&output_file);
 
     // Create output directory if it doesn't exist
     if let Some(parent) = output_path.parent() {
-        fs::create_dir_all(parent)
-            .map_err(|e| format!("Failed to create output directory: {}", e))?;
+        fs::create_dir_all(parent).map_err(ErsaError::io("Failed to create output directory"))?;
     }
 
+    // Read the previous output before it is overwritten
+    let previous = if args.diff {
+        fs::read_to_string(&output_path).ok()
+    } else {
+        None
+    };
+
     // Write output
-    fs::write(&output_path, preprocessed)
-        .map_err(|e| format!("Failed to write output file: {}", e))?;
+    fs::write(&output_path, &output.code).map_err(ErsaError::io("Failed to write output file"))?;
+
+    if let Some(previous) = previous {
+        match diff::unified_diff(
+            &previous,
+            &output.code,
+            &output_file,
+            crate::log::use_color(false),
+        ) {
+            Some(diff) => print!("{}", diff),
+            None => crate::log::info("Build output unchanged"),
+        }
+    }
 
     crate::log::success(&format!("Build complete: {}", output_path.display()));
 
+    Ok(output_path)
+}
+
+/// Write a depfile for the input (`--emit deps`) instead of building it
+fn emit_deps(args: BuildArgs) -> Result<()> {
+    let cwd = std::env::current_dir().map_err(ErsaError::io("Failed to get current directory"))?;
+    let (input_path, include_dirs) = input_path(&args)?;
+    if !input_path.exists() {
+        return Err(ErsaError::FileNotFound(input_path));
+    }
+
+    let options = preprocess_options(
+        &args,
+        preprocess::Language::from_path(&input_path),
+        include_dirs,
+    )?;
+    let target = crate::config::get().build_output(None)?;
+    let mut deps = source_files(&input_path, &options);
+    deps.extend(define_files(&args));
+    let contents = depfile(&target, &deps);
+
+    match args.output {
+        Some(output_file) => {
+            let output_path = cwd.join(output_file);
+            if let Some(parent) = output_path.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(ErsaError::io("Failed to create output directory"))?;
+            }
+            fs::write(&output_path, contents).map_err(ErsaError::io("Failed to write depfile"))?;
+            crate::log::success(&format!("Wrote depfile: {}", output_path.display()));
+        }
+        None => print!("{}", contents),
+    }
+
+    Ok(())
+}
+
+/// The entry file followed by every file it imports, directly or not, and
+/// every file it embeds with `import text`. Imports that can't be resolved
+/// are left out.
+fn source_files(input_path: &Path, options: &preprocess::PreprocessOptions) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let Ok(code) = fs::read_to_string(input_path) {
+        let base_path = input_path.parent().unwrap_or(Path::new("."));
+        files = preprocess::imports::dependencies(&code, base_path, options);
+    }
+    files.insert(0, input_path.to_path_buf());
+    files
+}
+
+/// The `--define-from` files, resolved against the current directory
+fn define_files(args: &BuildArgs) -> Vec<PathBuf> {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    args.define_from.iter().map(|file| cwd.join(file)).collect()
+}
+
+/// Format a Makefile rule `target: dep1 dep2 ...`, escaping spaces in paths
+fn depfile(target: &str, deps: &[PathBuf]) -> String {
+    let escape = |path: &str| path.replace(' ', "\\ ");
+    let mut rule = format!("{}:", escape(target));
+    for dep in deps {
+        rule.push(' ');
+        rule.push_str(&escape(&dep.display().to_string()));
+    }
+    rule.push('\n');
+    rule
+}
+
+/// The first option given that `-f -` can't honour, if any
+fn unsupported_with_stdin(args: &BuildArgs) -> Option<&'static str> {
+    [
+        (args.watch, "--watch"),
+        (args.emit == Emit::Deps, "--emit deps"),
+        (args.diff, "--diff"),
+        (args.timeout.is_some(), "--timeout"),
+    ]
+    .into_iter()
+    .find_map(|(given, flag)| given.then_some(flag))
+}
+
+/// The first option given that `--emit deps`, which doesn't build, can't
+/// honour, if any
+fn unsupported_with_deps(args: &BuildArgs) -> Option<&'static str> {
+    [
+        (args.watch, "--watch"),
+        (args.diff, "--diff"),
+        (args.target.is_some(), "--target"),
+    ]
+    .into_iter()
+    .find_map(|(given, flag)| given.then_some(flag))
+}
+
+/// The first option given that `ersa run` can't honour, if any
+pub fn unsupported_with_run(args: &BuildArgs) -> Option<&'static str> {
+    [
+        (args.file.as_deref() == Some("-"), "-f -"),
+        (args.watch, "--watch"),
+        (args.emit == Emit::Deps, "--emit deps"),
+    ]
+    .into_iter()
+    .find_map(|(given, flag)| given.then_some(flag))
+}
+
+/// Build source read from stdin (`-f -`).
+///
+/// Imports are resolved relative to the current directory. The result is
+/// written to `-o` if given, otherwise to stdout.
+fn build_stdin(args: BuildArgs) -> Result<()> {
+    let cwd = std::env::current_dir().map_err(ErsaError::io("Failed to get current directory"))?;
+    let include_dirs: Vec<PathBuf> = args.include_dirs.iter().map(|d| cwd.join(d)).collect();
+    let options = preprocess_options(&args, preprocess::Language::Gpc, include_dirs)?;
+
+    let output = build_reader(std::io::stdin().lock(), &cwd, &options)?;
+    for warning in &output.warnings {
+        crate::log::warn(warning);
+    }
+    check_strict(crate::cli::is_strict(), output.warnings.len())?;
+    check_warning_budget(args.fail_on_warning_count, output.warnings.len())?;
+    check_target(args.target.as_deref(), crate::config::get(), &output.code)?;
+
+    match args.output {
+        Some(output_file) => {
+            let output_path = cwd.join(output_file);
+            if let Some(parent) = output_path.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(ErsaError::io("Failed to create output directory"))?;
+            }
+            fs::write(&output_path, &output.code)
+                .map_err(ErsaError::io("Failed to write output file"))?;
+            crate::log::success(&format!("Build complete: {}", output_path.display()));
+        }
+        None => print!("{}", output.code),
+    }
+
     Ok(())
 }
+
+/// Fail under `--strict` if there are any warnings, before output is written
+fn check_strict(strict: bool, warnings: usize) -> Result<()> {
+    if strict && warnings > 0 {
+        return Err(ErsaError::Strict(warnings));
+    }
+    Ok(())
+}
+
+/// Fail if there are more `warnings` than `--fail-on-warning-count` allows
+fn check_warning_budget(budget: Option<usize>, warnings: usize) -> Result<()> {
+    match budget {
+        Some(budget) if warnings > budget => Err(ErsaError::WarningBudget {
+            count: warnings,
+            budget,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Check `code` against the `--target` profile, if one was given
+fn check_target(name: Option<&str>, config: &crate::config::Config, code: &str) -> Result<()> {
+    match name {
+        Some(name) => target::check(name, &target::profile(name, config)?, code),
+        None => Ok(()),
+    }
+}
+
+/// Read all of `reader` and build it, resolving imports against `base_path`
+fn build_reader(
+    mut reader: impl Read,
+    base_path: &Path,
+    options: &preprocess::PreprocessOptions,
+) -> Result<BuildOutput> {
+    let mut code = String::new();
+    reader
+        .read_to_string(&mut code)
+        .map_err(ErsaError::io("Failed to read stdin"))?;
+    build_source(&code, Path::new("<stdin>"), base_path, options)
+}
+
+fn preprocess_options(
+    args: &BuildArgs,
+    language: preprocess::Language,
+    include_dirs: Vec<PathBuf>,
+) -> Result<preprocess::PreprocessOptions> {
+    let cwd = std::env::current_dir().map_err(ErsaError::io("Failed to get current directory"))?;
+    let mut defines = Vec::new();
+    for file in &args.define_from {
+        let path = cwd.join(file);
+        let contents = fs::read_to_string(&path).map_err(ErsaError::io(format!(
+            "Failed to read defines file {}",
+            path.display()
+        )))?;
+        defines.extend(preprocess::macros::parse_defines_file(
+            &contents,
+            Path::new(file),
+        )?);
+    }
+    for define in &args.defines {
+        defines.push(preprocess::macros::parse_define(define)?);
+    }
+
+    Ok(preprocess::PreprocessOptions {
+        language,
+        passes: args.passes(),
+        defines,
+        keep_macro_comments: args.keep_macro_comments,
+        keep_going: args.keep_going,
+        include_dirs,
+        strict_macros: args.strict_macros,
+        warn_unused_macros: args.warn_unused_macros,
+        sequential_params: args.sequential_params,
+        print_imports: args.print_imports,
+        trace_macros: args.trace_macros,
+        format: args.format,
+        max_macro_body: args.max_macro_body,
+        max_imports: Some(args.max_imports),
+        reinline_imports: args.reinline_imports,
+    })
+}
+
+/// The file to build: the module at import path `entry` if given, otherwise
+/// `file` or the configured entry
+fn entry_path(
+    cwd: &Path,
+    entry: Option<&str>,
+    file: Option<String>,
+    include_dirs: &[PathBuf],
+) -> Result<PathBuf> {
+    match entry {
+        Some(module) => preprocess::imports::resolve_import(module, cwd, include_dirs),
+        None => Ok(cwd.join(crate::config::get().build_entry(file)?)),
+    }
+}
+
+/// Build `input_path` in memory without writing anything to disk.
+///
+/// Imports are resolved relative to the file's directory. This is the entry
+/// point for tools that want the built source rather than a file.
+pub fn build_file(
+    input_path: &Path,
+    options: &preprocess::PreprocessOptions,
+) -> Result<BuildOutput> {
+    // Check if input file exists
+    if !input_path.exists() {
+        return Err(ErsaError::FileNotFound(input_path.to_path_buf()));
+    }
+
+    // Read input file
+    let code =
+        fs::read_to_string(input_path).map_err(ErsaError::io("Failed to read input file"))?;
+
+    let base_path = input_path.parent().unwrap_or(Path::new("."));
+    build_source(&code, input_path, base_path, options)
+}
+
+/// Build `code` in memory. `name` is the file reported in lexical errors.
+pub fn build_source(
+    code: &str,
+    name: &Path,
+    base_path: &Path,
+    options: &preprocess::PreprocessOptions,
+) -> Result<BuildOutput> {
+    let code = preprocess::strip_bom(code);
+    preprocess::validate::check(code).map_err(|e| ErsaError::lexical(name, e))?;
+
+    // Preprocess the code
+    let mut warnings = Vec::new();
+    let preprocessed = preprocess::preprocess(code, base_path, options, &mut warnings)?;
+    if preprocessed.trim().is_empty() {
+        warnings.push(format!("Build output of {} is empty", name.display()));
+    }
+
+    Ok(BuildOutput {
+        code: match_trailing_newline(preprocessed, code),
+        warnings,
+    })
+}
+
+/// Run [`build_file`] on a worker thread, giving up after `timeout`.
+///
+/// On timeout the worker is abandoned rather than stopped; it only touches
+/// memory, so letting it finish in the background is harmless.
+pub fn build_file_with_timeout(
+    input_path: &Path,
+    options: &preprocess::PreprocessOptions,
+    timeout: Duration,
+) -> Result<BuildOutput> {
+    let input_path = input_path.to_path_buf();
+    let options = options.clone();
+    run_with_timeout(timeout, move || build_file(&input_path, &options))
+}
+
+/// Run `job` on a worker thread, giving up after `timeout`
+fn run_with_timeout<T: Send + 'static>(
+    timeout: Duration,
+    job: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let (sender, receiver) = mpsc::channel();
+
+    std::thread::Builder::new()
+        .name("ersa-build".to_string())
+        .spawn(move || {
+            let _ = sender.send(job());
+        })
+        .map_err(ErsaError::io("Failed to start build thread"))?;
+
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(ErsaError::BuildTimeout(timeout.as_secs())),
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            Err(ErsaError::Other("Build thread panicked".to_string()))
+        }
+    }
+}
+
+/// End `output` with a newline only if `source` did, so passes that add or
+/// strip a final newline don't show up in diffs
+fn match_trailing_newline(output: String, source: &str) -> String {
+    let mut output = output.trim_end_matches(['\r', '\n']).to_string();
+    if source.ends_with("\r\n") {
+        output.push_str("\r\n");
+    } else if source.ends_with('\n') {
+        output.push('\n');
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trailing_newline_preserved() {
+        assert_eq!(match_trailing_newline("a\n\n".to_string(), "src\n"), "a\n");
+        assert_eq!(match_trailing_newline("a".to_string(), "src\n"), "a\n");
+        assert_eq!(
+            match_trailing_newline("a\r\n".to_string(), "src\r\n"),
+            "a\r\n"
+        );
+    }
+
+    #[test]
+    fn test_build_file_in_memory() {
+        let dir = std::env::temp_dir().join(format!("ersa_build_file_{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(
+            dir.join("main.gpc"),
+            "import lib/util;\nmain {\n    x = double(4)!;\n}\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("lib").join("util.gpc"),
+            "define! double(v) { v * 2 }",
+        )
+        .unwrap();
+
+        let output = build_file(&dir.join("main.gpc"), &Default::default()).unwrap();
+
+        assert!(output.code.contains("8;"));
+        assert!(!output.code.contains("define!"));
+        assert!(output.code.ends_with("}\n"));
+        assert!(output.warnings.is_empty());
+        assert!(!dir.join("build").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_build_module_entry() {
+        let dir = std::env::temp_dir().join(format!("ersa_build_entry_{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("main.gpc"), "main { }\n").unwrap();
+        fs::write(
+            dir.join("lib").join("util.gpc"),
+            "import consts;\nint scaled = LIMIT! * 2;\n",
+        )
+        .unwrap();
+        fs::write(dir.join("lib").join("consts.gpc"), "define! LIMIT { 50 }").unwrap();
+
+        let input = entry_path(&dir, Some("lib/util"), None, &[]).unwrap();
+        assert!(input.ends_with("lib/util.gpc"));
+
+        let output = build_file(&input, &Default::default()).unwrap();
+        assert!(output.code.contains("int scaled = 100;"));
+        assert!(!output.code.contains("main"));
+
+        assert!(entry_path(&dir, Some("lib/nope"), None, &[]).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_build_is_deterministic() {
+        let dir = std::env::temp_dir().join(format!("ersa_build_repro_{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(
+            dir.join("main.gpc"),
+            "import lib/a;\nimport lib/b;\nmain {\n    x = A(1)! + B!;\n}\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("lib").join("a.gpc"),
+            "define! A(v) { v * 2 }\ndefine! UNUSED_A { 0 }\nint a;",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("lib").join("b.gpc"),
+            "define! B { 3 }\ndefine! UNUSED_B { 0 }\nint b;",
+        )
+        .unwrap();
+
+        let options = preprocess::PreprocessOptions {
+            keep_macro_comments: true,
+            ..Default::default()
+        };
+        let first = build_file(&dir.join("main.gpc"), &options).unwrap();
+        for _ in 0..5 {
+            let again = build_file(&dir.join("main.gpc"), &options).unwrap();
+            assert_eq!(again.code.as_bytes(), first.code.as_bytes());
+            assert_eq!(again.warnings, first.warnings);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_build_from_stdin() {
+        let dir = std::env::temp_dir().join(format!("ersa_build_stdin_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("consts.gpc"), "define! SPEED { 4 }").unwrap();
+
+        let stdin = "import consts;\nmain {\n    x = SPEED! * 2;\n}\n".as_bytes();
+        let output = build_reader(stdin, &dir, &Default::default()).unwrap();
+        assert_eq!(output.code.trim_start(), "main {\n    x = 8;\n}\n");
+
+        let err = build_reader("main { \"open".as_bytes(), &dir, &Default::default()).unwrap_err();
+        assert!(err.to_string().starts_with("<stdin>:1:"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_build_strips_bom() {
+        let dir = std::env::temp_dir().join(format!("ersa_build_bom_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.gpc"), "\u{feff}int speed = 2 * 2;\n").unwrap();
+        fs::write(dir.join("main.gpc"), "\u{feff}import lib;\nmain {\n}\n").unwrap();
+
+        let output = build_file(&dir.join("main.gpc"), &Default::default()).unwrap();
+        assert!(!output.code.contains('\u{feff}'));
+        assert!(output.code.starts_with("int speed = 4;\n"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_empty_output_warns() {
+        let output =
+            build_reader("\n  \n".as_bytes(), Path::new("."), &Default::default()).unwrap();
+        assert_eq!(output.warnings, ["Build output of <stdin> is empty"]);
+
+        let output =
+            build_reader("int x;\n".as_bytes(), Path::new("."), &Default::default()).unwrap();
+        assert!(output.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_target_size_limit() {
+        let dir = std::env::temp_dir().join(format!("ersa_build_target_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let entry = dir.join("main.gpc");
+        let output = dir.join("out.gpc");
+        fs::write(&entry, "main {\n    x = 1 + 2;\n}\n").unwrap();
+
+        let mut config = crate::config::Config::default();
+        config
+            .targets
+            .insert("tiny".to_string(), target::TargetProfile { max_size: 16 });
+        let args = |target: &str| {
+            parse_args(&[
+                "-f",
+                entry.to_str().unwrap(),
+                "-o",
+                output.to_str().unwrap(),
+                "--target",
+                target,
+            ])
+        };
+
+        let (result, _) = crate::log::capture(|| build_with_strict(args("tiny"), false, &config));
+        assert!(matches!(
+            result,
+            Err(ErsaError::TargetLimit {
+                size: 20,
+                limit: 16,
+                ..
+            })
+        ));
+        assert!(!output.exists());
+
+        let (result, _) = crate::log::capture(|| build_with_strict(args("zen"), false, &config));
+        assert_eq!(result.unwrap(), output);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_warning_budget() {
+        let output = build_reader(
+            "main {\n    x = 1 / 0;\n    y = 2 / 0;\n}\n".as_bytes(),
+            Path::new("."),
+            &Default::default(),
+        )
+        .unwrap();
+        let count = output.warnings.len();
+        assert_eq!(count, 2);
+
+        assert!(check_warning_budget(None, count).is_ok());
+        assert!(check_warning_budget(Some(10), count).is_ok());
+        assert!(check_warning_budget(Some(2), count).is_ok());
+        let err = check_warning_budget(Some(1), count).unwrap_err();
+        assert!(matches!(
+            err,
+            ErsaError::WarningBudget {
+                count: 2,
+                budget: 1
+            }
+        ));
+    }
+
+    fn parse_args(args: &[&str]) -> BuildArgs {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            build: BuildArgs,
+        }
+
+        let argv = std::iter::once("test").chain(args.iter().copied());
+        <Cli as clap::Parser>::parse_from(argv).build
+    }
+
+    #[test]
+    fn test_stdin_rejects_file_only_options() {
+        assert_eq!(unsupported_with_stdin(&parse_args(&["-f", "-"])), None);
+        for (flag, args) in [
+            ("--watch", &["-f", "-", "--watch"][..]),
+            ("--emit deps", &["-f", "-", "--emit", "deps"]),
+            ("--diff", &["-f", "-", "--diff"]),
+            ("--timeout", &["-f", "-", "--timeout", "5"]),
+        ] {
+            assert_eq!(unsupported_with_stdin(&parse_args(args)), Some(flag));
+        }
+    }
+
+    #[test]
+    fn test_deps_rejects_build_only_options() {
+        assert_eq!(
+            unsupported_with_deps(&parse_args(&["--emit", "deps"])),
+            None
+        );
+        for (flag, args) in [
+            ("--watch", &["--emit", "deps", "--watch"][..]),
+            ("--diff", &["--emit", "deps", "--diff"]),
+            ("--target", &["--emit", "deps", "--target", "zen"]),
+        ] {
+            assert_eq!(unsupported_with_deps(&parse_args(args)), Some(flag));
+        }
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let err = runtime
+            .block_on(run(parse_args(&["--emit", "deps", "--watch"])))
+            .unwrap_err();
+        assert!(matches!(err, ErsaError::Other(ref msg) if msg.contains("--watch")));
+    }
+
+    #[test]
+    fn test_run_rejects_build_only_options() {
+        assert_eq!(unsupported_with_run(&parse_args(&["-f", "main.gpc"])), None);
+        for (flag, args) in [
+            ("--watch", &["--watch"][..]),
+            ("--watch", &["--watch", "--clear"]),
+            ("-f -", &["-f", "-"]),
+            ("--emit deps", &["--emit", "deps"]),
+        ] {
+            assert_eq!(unsupported_with_run(&parse_args(args)), Some(flag));
+        }
+    }
+
+    #[test]
+    fn test_strict_fails_before_writing() {
+        let dir = std::env::temp_dir().join(format!("ersa_build_strict_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let entry = dir.join("main.gpc");
+        let output = dir.join("out.gpc");
+        fs::write(&entry, "main { x = 1 / 0; }\n").unwrap();
+        let args = parse_args(&[
+            "-f",
+            entry.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+        ]);
+
+        let (result, lines) =
+            crate::log::capture(|| build_with_strict(args.clone(), true, &Default::default()));
+        assert!(matches!(result, Err(ErsaError::Strict(1))));
+        assert!(!output.exists());
+        assert!(!lines.iter().any(|l| l.contains("Build complete")));
+
+        let (result, _) =
+            crate::log::capture(|| build_with_strict(args, false, &Default::default()));
+        assert_eq!(result.unwrap(), output);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_define_files_are_inputs() {
+        let args = parse_args(&["--define-from", "defs.env"]);
+        let files = define_files(&args);
+        assert_eq!(files, [std::env::current_dir().unwrap().join("defs.env")]);
+    }
+
+    #[test]
+    fn test_depfile_lists_imports() {
+        let dir = std::env::temp_dir().join(format!("ersa_depfile_{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(
+            dir.join("main.gpc"),
+            "import lib/a;\nimport lib/b;\nimport text \"banner.txt\" as BANNER;\nmain {}\n",
+        )
+        .unwrap();
+        fs::write(dir.join("banner.txt"), "hello").unwrap();
+        fs::write(dir.join("lib").join("a.gpc"), "import lib/c;\n").unwrap();
+        fs::write(dir.join("lib").join("b.gpc"), "int b;\n").unwrap();
+        fs::write(dir.join("lib").join("c.gpc"), "int c;\n").unwrap();
+
+        let options = preprocess::PreprocessOptions {
+            include_dirs: vec![dir.clone()],
+            ..Default::default()
+        };
+        let files = source_files(&dir.join("main.gpc"), &options);
+        let rule = depfile("build/my out.gpc", &files);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(rule.starts_with("build/my\\ out.gpc: "));
+        assert!(rule.ends_with('\n'));
+        assert_eq!(files.len(), 5);
+        assert_eq!(files[0], dir.join("main.gpc"));
+        assert!(files.contains(&dir.join("banner.txt")));
+        for name in ["a", "b", "c"] {
+            let path = dir.join("lib").join(format!("{}.gpc", name));
+            assert!(files.contains(&path));
+            assert!(rule.contains(&path.display().to_string()));
+        }
+    }
+
+    #[test]
+    fn test_build_file_missing() {
+        let err = build_file(Path::new("/nonexistent/main.gpc"), &Default::default()).unwrap_err();
+        assert!(matches!(err, ErsaError::FileNotFound(_)));
+    }
+
+    #[test]
+    fn test_build_timeout() {
+        let dir = std::env::temp_dir().join(format!("ersa_build_timeout_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("main.gpc"), "main { x = 1 + 2 * 3 - 4; }\n").unwrap();
+
+        // A job that only finishes once the test lets it, after the timeout
+        let (release, blocked) = mpsc::channel::<()>();
+        let err = run_with_timeout(Duration::from_millis(10), move || {
+            let _ = blocked.recv();
+            Ok(())
+        })
+        .unwrap_err();
+        assert!(matches!(err, ErsaError::BuildTimeout(0)));
+        drop(release);
+
+        let output = build_file_with_timeout(
+            &dir.join("main.gpc"),
+            &Default::default(),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        assert!(output.code.contains("x = 3;"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_no_trailing_newline_preserved() {
+        assert_eq!(match_trailing_newline("a\n".to_string(), "src"), "a");
+        assert_eq!(match_trailing_newline("a".to_string(), "src"), "a");
+    }
+}
@@ -0,0 +1,87 @@
+/// Remove build-only `//!` comments from `code`.
+///
+/// A line holding nothing but a `//!` comment is dropped entirely; otherwise
+/// only the comment and the whitespace before it are removed. Regular `//` and
+/// `/* */` comments are left alone, as are `//!` sequences inside strings.
+pub fn strip_build_comments(code: &str) -> String {
+    let mut result = String::with_capacity(code.len());
+    let mut in_block_comment = false;
+
+    for line in code.split_inclusive('\n') {
+        match find_build_comment(line, &mut in_block_comment) {
+            Some(start) => {
+                let kept = line[..start].trim_end();
+                if kept.is_empty() {
+                    continue;
+                }
+                result.push_str(kept);
+                if line.ends_with('\n') {
+                    result.push('\n');
+                }
+            }
+            None => result.push_str(line),
+        }
+    }
+
+    result
+}
+
+/// Byte offset of the `//!` that starts a build-only comment on `line`, if any
+fn find_build_comment(line: &str, in_block_comment: &mut bool) -> Option<usize> {
+    let bytes = line.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if *in_block_comment {
+            if line[i..].starts_with("*/") {
+                *in_block_comment = false;
+                i += 2;
+            } else {
+                i += 1;
+            }
+            continue;
+        }
+
+        match bytes[i] {
+            b'"' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' && bytes[i] != b'\n' {
+                    if bytes[i] == b'\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                i += 1;
+            }
+            b'/' if line[i..].starts_with("//!") => return Some(i),
+            b'/' if line[i..].starts_with("//") => return None,
+            b'/' if line[i..].starts_with("/*") => {
+                *in_block_comment = true;
+                i += 2;
+            }
+            _ => i += 1,
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_only_comments_removed() {
+        let code = "//! internal note\nint a; //! why a exists\n// kept\nint b; // kept too\n";
+        assert_eq!(
+            strip_build_comments(code),
+            "int a;\n// kept\nint b; // kept too\n"
+        );
+    }
+
+    #[test]
+    fn test_build_comment_markers_in_strings_and_blocks() {
+        let code = "s = \"//! not a comment\";\n/* //! still a block\n//! block */ int c;\n";
+        assert_eq!(strip_build_comments(code), code);
+    }
+}
@@ -0,0 +1,107 @@
+use super::format::BraceScanner;
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+/// A function or macro defined outside any block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Definition {
+    pub kind: &'static str,
+    pub name: String,
+    /// 1-based line of the definition
+    pub line: usize,
+}
+
+/// Matches a `function NAME` or `define! NAME` line; compiled once since it
+/// runs on every inlined file
+static DEFINITION_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s*(?:function\s+|define!\s*)([A-Za-z_]\w*)").unwrap());
+
+/// The top-level `function NAME` and `define! NAME` definitions in `code`,
+/// in source order
+pub fn top_level(code: &str) -> Vec<Definition> {
+    let mut scanner = BraceScanner::default();
+    let mut found = Vec::new();
+
+    for (index, line) in code.lines().enumerate() {
+        if scanner.depth == 0
+            && !scanner.in_block_comment
+            && let Some(caps) = DEFINITION_RE.captures(line)
+        {
+            found.push(Definition {
+                kind: if caps[0].trim_start().starts_with("function") {
+                    "Function"
+                } else {
+                    "Macro"
+                },
+                name: caps[1].to_string(),
+                line: index + 1,
+            });
+        }
+        scanner.scan(line);
+    }
+
+    found
+}
+
+/// Where each top-level name was first defined while inlining, to report
+/// names defined again by a later file
+#[derive(Debug, Default)]
+pub struct Definitions {
+    /// File (`None` for the entry file) and line of each name's first definition
+    first: HashMap<String, (Option<PathBuf>, usize)>,
+}
+
+impl Definitions {
+    /// Record the definitions in `code`, the contents of `file`, returning a
+    /// warning for each name that was already defined
+    pub fn add(&mut self, code: &str, file: Option<&Path>) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for definition in top_level(code) {
+            match self.first.get(&definition.name) {
+                Some((first_file, first_line)) => warnings.push(format!(
+                    "{} '{}' is defined at {} and again at {}",
+                    definition.kind,
+                    definition.name,
+                    location(first_file.as_deref(), *first_line),
+                    location(file, definition.line)
+                )),
+                None => {
+                    self.first.insert(
+                        definition.name,
+                        (file.map(Path::to_path_buf), definition.line),
+                    );
+                }
+            }
+        }
+        warnings
+    }
+}
+
+fn location(file: Option<&Path>, line: usize) -> String {
+    match file {
+        Some(file) => format!("{}:{}", file.display(), line),
+        None => format!("line {} of the entry file", line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_top_level_definitions() {
+        let code = "define! MAX { 10 }\nfunction foo() {\n    function_call();\n}\n/*\nfunction hidden() {}\n*/\nmain {\n  define! inner {}\n}\n";
+        let names: Vec<(&str, usize)> = top_level(code).iter().map(|d| (d.kind, d.line)).collect();
+        assert_eq!(names, [("Macro", 1), ("Function", 2)]);
+        assert_eq!(top_level(code)[1].name, "foo");
+    }
+
+    #[test]
+    fn test_identifiers_starting_with_function() {
+        let code = "function_count = 0;\nfunctions(1);\ndefine!MAX { 1 }\n";
+        let names: Vec<String> = top_level(code).into_iter().map(|d| d.name).collect();
+        assert_eq!(names, ["MAX"]);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::error::{ErsaError, Result};
+use regex::Regex;
+use std::sync::LazyLock;
+
+/// Matches an `env!("NAME")` call at the start of the text
+static ENV_CALL_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^env!\(\s*"([^"]*)"\s*\)"#).unwrap());
+
+/// Replace each `env!("NAME")` in `code` with the value of the environment
+/// variable `NAME`, inserted as is. Calls inside strings and comments are
+/// left alone. An unset variable is an error.
+pub fn expand_env(code: &str) -> Result<String> {
+    expand_env_with(code, |name| std::env::var(name).ok())
+}
+
+fn expand_env_with(code: &str, env: impl Fn(&str) -> Option<String>) -> Result<String> {
+    if !code.contains("env!") {
+        return Ok(code.to_string());
+    }
+
+    let mut result = String::with_capacity(code.len());
+    let mut i = 0;
+
+    while i < code.len() {
+        let rest = &code[i..];
+        let skip = if let Some(len) = super::scan::skipped_len(rest) {
+            len
+        } else if let Some(caps) = ENV_CALL_RE.captures(rest)
+            && !code[..i].ends_with(|c: char| c.is_alphanumeric() || c == '_')
+        {
+            let name = &caps[1];
+            let value = env(name).ok_or_else(|| ErsaError::UndefinedEnv(name.to_string()))?;
+            result.push_str(&value);
+            i += caps[0].len();
+            continue;
+        } else {
+            rest.chars().next().map_or(1, char::len_utf8)
+        };
+
+        result.push_str(&rest[..skip]);
+        i += skip;
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock_env(name: &str) -> Option<String> {
+        (name == "BUILD_ID").then(|| "42".to_string())
+    }
+
+    #[test]
+    fn test_set_variable() {
+        let code = "int id = env!(\"BUILD_ID\");\nint b = env!( \"BUILD_ID\" ) + 1;\n";
+        assert_eq!(
+            expand_env_with(code, mock_env).unwrap(),
+            "int id = 42;\nint b = 42 + 1;\n"
+        );
+    }
+
+    #[test]
+    fn test_unset_variable() {
+        let err = expand_env_with("x = env!(\"MISSING\");", mock_env).unwrap_err();
+        assert!(matches!(err, ErsaError::UndefinedEnv(ref name) if name == "MISSING"));
+    }
+
+    #[test]
+    fn test_strings_and_comments_untouched() {
+        let code = "s = \"env!(\\\"MISSING\\\")\"; // env!(\"MISSING\")\n/* env!(\"MISSING\") */ myenv!(\"X\")";
+        assert_eq!(expand_env_with(code, mock_env).unwrap(), code);
+    }
+}
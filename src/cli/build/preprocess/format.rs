@@ -0,0 +1,148 @@
+/// How the built output is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Format {
+    /// Leave the output as the passes produced it
+    #[default]
+    None,
+    /// Reindent every line by brace depth, four spaces per level, and
+    /// collapse runs of blank lines into one
+    Pretty,
+    /// Drop indentation and blank lines
+    Compact,
+}
+
+const INDENT: &str = "    ";
+
+/// Lay out `code` in the given style.
+///
+/// Only whitespace at the start and end of lines is changed, so the code
+/// means the same thing in every style. Lines inside `/* */` comments are
+/// left as they are.
+pub fn format(code: &str, style: Format) -> String {
+    if style == Format::None {
+        return code.to_string();
+    }
+
+    let mut result = String::with_capacity(code.len());
+    let mut scanner = BraceScanner::default();
+    let mut after_blank = false;
+
+    for line in code.split_inclusive('\n') {
+        let newline = line.ends_with('\n');
+        let in_comment = scanner.in_block_comment;
+        let depth = scanner.depth;
+        let text = line.trim_end_matches('\n');
+        scanner.scan(text);
+
+        if in_comment {
+            result.push_str(line);
+            after_blank = false;
+            continue;
+        }
+
+        let trimmed = text.trim();
+        if trimmed.is_empty() {
+            if style == Format::Pretty && newline && !after_blank {
+                result.push('\n');
+            }
+            after_blank = true;
+            continue;
+        }
+        after_blank = false;
+
+        if style == Format::Pretty {
+            // A line opening with `}` belongs to the enclosing level
+            let level = if trimmed.starts_with('}') {
+                depth.saturating_sub(1)
+            } else {
+                depth
+            };
+            result.push_str(&INDENT.repeat(level));
+        }
+        result.push_str(trimmed);
+        if newline {
+            result.push('\n');
+        }
+    }
+
+    result
+}
+
+/// Tracks brace depth across lines, ignoring braces in strings and comments
+#[derive(Debug, Default)]
+pub(super) struct BraceScanner {
+    /// Braces still open at the end of the last scanned line
+    pub depth: usize,
+    pub in_block_comment: bool,
+}
+
+impl BraceScanner {
+    /// Update the state with one line, given without its newline
+    pub fn scan(&mut self, line: &str) {
+        let bytes = line.as_bytes();
+        let mut i = 0;
+
+        while i < bytes.len() {
+            if self.in_block_comment {
+                if line[i..].starts_with("*/") {
+                    self.in_block_comment = false;
+                    i += 2;
+                } else {
+                    i += 1;
+                }
+                continue;
+            }
+
+            match bytes[i] {
+                b'"' => {
+                    i += 1;
+                    while i < bytes.len() && bytes[i] != b'"' {
+                        if bytes[i] == b'\\' {
+                            i += 1;
+                        }
+                        i += 1;
+                    }
+                }
+                b'/' if line[i..].starts_with("//") => return,
+                b'/' if line[i..].starts_with("/*") => {
+                    self.in_block_comment = true;
+                    i += 1;
+                }
+                b'{' => self.depth += 1,
+                b'}' => self.depth = self.depth.saturating_sub(1),
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pretty_reindents() {
+        let code = "int a;\n\n\nmain {\n  if (a) {\n\t\tb = \"{\";\n      }\n  }\n";
+        assert_eq!(
+            format(code, Format::Pretty),
+            "int a;\n\nmain {\n    if (a) {\n        b = \"{\";\n    }\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_pretty_keeps_block_comments() {
+        let code = "main {\n/* {\n   keep\n*/ a = 1; // }\nb = 2;\n}";
+        assert_eq!(
+            format(code, Format::Pretty),
+            "main {\n    /* {\n   keep\n*/ a = 1; // }\n    b = 2;\n}"
+        );
+    }
+
+    #[test]
+    fn test_compact_and_none() {
+        let code = "main {\n\n    a = 1;   \n}\n";
+        assert_eq!(format(code, Format::Compact), "main {\na = 1;\n}\n");
+        assert_eq!(format(code, Format::None), code);
+    }
+}
@@ -1,67 +1,960 @@
+use super::PreprocessOptions;
+use super::definitions::Definitions;
+use super::diagnostics::Diagnostic;
+use crate::error::{ErsaError, Result};
 use regex::Regex;
 use std::collections::HashSet;
 use std::fs;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
 
+/// An `import` statement found in source code
+#[derive(Debug, Clone)]
+pub struct Import {
+    /// Byte range of the whole statement
+    pub span: Range<usize>,
+    /// The path as written in the source
+    pub path: String,
+    /// `@once`: skip this import if the file was already inlined, even
+    /// with `reinline_imports`
+    pub once: bool,
+    /// `@optional`: skip this import with a warning if the file doesn't exist
+    pub optional: bool,
+    /// `import text "file" as NAME;`: inline the file as a string constant
+    /// called `NAME` instead of as code
+    pub text_alias: Option<String>,
+}
+
+/// Matches an `import` statement; compiled once since it runs on every inlined file
+static IMPORT_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"import\s+(?:text\s+"([^"]+)"\s+as\s+([A-Za-z_]\w*)|"([^"]+)"|([^\s;@]+))((?:\s*@\w+)*)\s*;?"#,
+    )
+    .unwrap()
+});
+
+/// Find all import statements in `code`, in source order
+pub fn find_imports(code: &str) -> Result<Vec<Import>> {
+    IMPORT_RE
+        .captures_iter(code)
+        .map(|cap| {
+            let mut import = Import {
+                span: cap.get(0).unwrap().range(),
+                path: [1, 3, 4]
+                    .into_iter()
+                    .find_map(|i| cap.get(i))
+                    .unwrap()
+                    .as_str()
+                    .to_string(),
+                once: false,
+                optional: false,
+                text_alias: cap.get(2).map(|m| m.as_str().to_string()),
+            };
+
+            for attribute in cap[5].split('@').map(str::trim).filter(|a| !a.is_empty()) {
+                match attribute {
+                    "once" => import.once = true,
+                    "optional" => import.optional = true,
+                    _ => {
+                        return Err(ErsaError::Parse(format!(
+                            "Unknown attribute '@{}' on import '{}'",
+                            attribute, import.path
+                        )));
+                    }
+                }
+            }
+
+            Ok(import)
+        })
+        .collect()
+}
+
+/// Resolve an import path to a canonical file path.
+///
+/// The path is looked up relative to `base_path` first, then in each of
+/// `include_dirs` in order.
+pub fn resolve_import(
+    path_str: &str,
+    base_path: &Path,
+    include_dirs: &[PathBuf],
+) -> Result<PathBuf> {
+    let path_with_ext = if path_str.ends_with(".gpc") || path_str.ends_with(".gpx") {
+        path_str.to_string()
+    } else {
+        format!("{}.gpc", path_str)
+    };
+    resolve_file(path_str, &path_with_ext, base_path, include_dirs)
+}
+
+/// Look `file` up relative to `base_path`, then in each of `include_dirs`.
+/// `path_str` is the path as written, for the error.
+fn resolve_file(
+    path_str: &str,
+    file: &str,
+    base_path: &Path,
+    include_dirs: &[PathBuf],
+) -> Result<PathBuf> {
+    let mut tried = Vec::new();
+    let mut first_error = None;
+
+    for dir in std::iter::once(base_path).chain(include_dirs.iter().map(PathBuf::as_path)) {
+        let full_path = dir.join(file);
+        match full_path.canonicalize() {
+            Ok(canonical) => return Ok(canonical),
+            Err(e) => {
+                first_error.get_or_insert(e);
+                tried.push(full_path);
+            }
+        }
+    }
+
+    Err(ErsaError::ImportNotFound {
+        path: path_str.to_string(),
+        tried,
+        source: first_error.unwrap(),
+    })
+}
+
+/// `contents` as a GPC string literal
+fn string_literal(contents: &str) -> String {
+    let mut literal = String::with_capacity(contents.len() + 2);
+    literal.push('"');
+    for ch in contents.chars() {
+        match ch {
+            '\\' => literal.push_str("\\\\"),
+            '"' => literal.push_str("\\\""),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            _ => literal.push(ch),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Inline the imports in `code`, appending skipped `@optional` imports to `warnings`.
+///
+/// With `options.keep_going`, imports that can't be found or read are replaced
+/// by a comment and all of them are reported together at the end.
 pub fn process_imports(
     code: &str,
     base_path: &Path,
     visited: &mut HashSet<PathBuf>,
-) -> Result<String, String> {
-    let re = Regex::new(r#"import\s+(?:"([^"]+)"|([^\s;]+))\s*;?"#)
-        .map_err(|e| format!("Regex compilation error: {}", e))?;
+    options: &PreprocessOptions,
+    warnings: &mut Vec<String>,
+) -> Result<String> {
+    let mut inliner = Inliner {
+        visited,
+        options,
+        warnings,
+        errors: Vec::new(),
+        inlined: 0,
+        stack: Vec::new(),
+        assets: HashSet::new(),
+        definitions: Definitions::default(),
+    };
+
+    let mut result = String::with_capacity(code.len());
+    inliner.inline(code, base_path, 0, &mut result)?;
+
+    if !inliner.errors.is_empty() {
+        return Err(ErsaError::Imports(inliner.errors));
+    }
+    Ok(result)
+}
+
+/// Inline every import of `code` that can be inlined, leaving a comment in
+/// place of the ones that fail
+pub fn inline_available(code: &str, base_path: &Path, options: &PreprocessOptions) -> String {
+    inline_lenient(code, base_path, options).0
+}
+
+/// Every file `code` imports, directly or not, sorted, including files
+/// embedded with `import text`. Imports that fail to resolve are left out.
+pub fn dependencies(code: &str, base_path: &Path, options: &PreprocessOptions) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = inline_lenient(code, base_path, options)
+        .1
+        .into_iter()
+        .collect();
+    files.sort();
+    files
+}
+
+fn inline_lenient(
+    code: &str,
+    base_path: &Path,
+    options: &PreprocessOptions,
+) -> (String, HashSet<PathBuf>) {
+    let options = PreprocessOptions {
+        keep_going: true,
+        print_imports: false,
+        ..options.clone()
+    };
+    let mut visited = HashSet::new();
+    let mut warnings = Vec::new();
+    let mut inliner = Inliner {
+        visited: &mut visited,
+        options: &options,
+        warnings: &mut warnings,
+        errors: Vec::new(),
+        inlined: 0,
+        stack: Vec::new(),
+        assets: HashSet::new(),
+        definitions: Definitions::default(),
+    };
+
+    let mut result = String::with_capacity(code.len());
+    if inliner.inline(code, base_path, 0, &mut result).is_err() {
+        result = code.to_string();
+    }
+    let assets = std::mem::take(&mut inliner.assets);
+    visited.extend(assets);
+    (result, visited)
+}
+
+/// Diagnose the imports in `code`.
+///
+/// Each statement is inlined on its own, so a failure anywhere below it is
+/// reported at that statement's span.
+pub fn diagnostics(code: &str, base_path: &Path, options: &PreprocessOptions) -> Vec<Diagnostic> {
+    let imports = match find_imports(code) {
+        Ok(imports) => imports,
+        Err(err) => return vec![Diagnostic::error(0..0, err.to_string())],
+    };
+
+    let mut diagnostics = Vec::new();
+    let mut visited = HashSet::new();
+    for import in imports {
+        let mut warnings = Vec::new();
+        let statement = &code[import.span.clone()];
+        let result = process_imports(statement, base_path, &mut visited, options, &mut warnings);
+
+        for warning in warnings {
+            diagnostics.push(Diagnostic::warning(import.span.clone(), warning));
+        }
+        match result {
+            Ok(_) => {}
+            Err(ErsaError::Imports(errors)) => {
+                for err in errors {
+                    diagnostics.push(Diagnostic::error(import.span.clone(), err.to_string()));
+                }
+            }
+            Err(err) => diagnostics.push(Diagnostic::error(import.span.clone(), err.to_string())),
+        }
+    }
+    diagnostics
+}
+
+/// State shared by every file in one import walk
+struct Inliner<'a> {
+    /// Every file inlined so far
+    visited: &'a mut HashSet<PathBuf>,
+    options: &'a PreprocessOptions,
+    warnings: &'a mut Vec<String>,
+    /// Failures recorded instead of returned under `keep_going`
+    errors: Vec<ErsaError>,
+    /// Files inlined so far, checked against `options.max_imports`
+    inlined: usize,
+    /// Files currently being inlined, outermost first
+    stack: Vec<PathBuf>,
+    /// Files embedded with `import text`, which aren't tracked as visited
+    assets: HashSet<PathBuf>,
+    /// Top-level names defined so far, to warn about duplicates
+    definitions: Definitions,
+}
+
+impl Inliner<'_> {
+    /// Append `code` to `out` with its imports inlined.
+    ///
+    /// Nested imports are written straight into the same buffer, so each file's
+    /// contents are copied once no matter how deep the import chain is. `depth`
+    /// is how many imports deep `code` is, for verbose progress logging.
+    fn inline(
+        &mut self,
+        code: &str,
+        base_path: &Path,
+        depth: usize,
+        out: &mut String,
+    ) -> Result<()> {
+        let duplicates = self
+            .definitions
+            .add(code, self.stack.last().map(PathBuf::as_path));
+        self.warnings.extend(duplicates);
+
+        let mut last_end = 0;
+
+        for import in find_imports(code)? {
+            out.push_str(&code[last_end..import.span.start]);
+            last_end = import.span.end;
+
+            if let Some(alias) = &import.text_alias {
+                self.inline_text(&import, alias, base_path, out)?;
+                continue;
+            }
+
+            let canonical =
+                match resolve_import(&import.path, base_path, &self.options.include_dirs) {
+                    Ok(canonical) => canonical,
+                    Err(ErsaError::ImportNotFound { path, .. }) if import.optional => {
+                        self.warnings.push(format!(
+                            "Skipping optional import '{}': file not found",
+                            path
+                        ));
+                        continue;
+                    }
+                    Err(e) => {
+                        self.recover(e, &import, out)?;
+                        continue;
+                    }
+                };
+
+            // Only a file still being inlined above us is a cycle; one already
+            // fully inlined elsewhere (a diamond) is skipped unless re-inlining
+            if self.stack.contains(&canonical) {
+                return Err(ErsaError::CircularImport(canonical));
+            }
+            if self.visited.contains(&canonical) && (import.once || !self.options.reinline_imports)
+            {
+                continue;
+            }
+            self.visited.insert(canonical.clone());
+
+            self.inlined += 1;
+            if let Some(max) = self.options.max_imports
+                && self.inlined > max
+            {
+                return Err(ErsaError::TooManyImports(max));
+            }
+
+            let imported_code = match fs::read_to_string(&canonical) {
+                Ok(imported_code) => imported_code,
+                Err(e) => {
+                    let context = format!("Failed to read imported file '{}'", canonical.display());
+                    self.recover(ErsaError::io(context)(e), &import, out)?;
+                    continue;
+                }
+            };
+
+            let imported_code = super::strip_bom(&imported_code);
+            super::validate::check(imported_code).map_err(|e| ErsaError::lexical(&canonical, e))?;
+
+            let imported_base = canonical.parent().ok_or_else(|| {
+                ErsaError::Other(format!(
+                    "Failed to get parent directory of {}",
+                    canonical.display()
+                ))
+            })?;
+            if self.options.print_imports {
+                crate::log::plain_err(&canonical.display().to_string());
+            }
+            crate::log::vinfo(&format!(
+                "Entering import {} (depth {})",
+                canonical.display(),
+                depth + 1
+            ));
+            out.reserve(imported_code.len());
+            self.stack.push(canonical.clone());
+            self.inline(imported_code, imported_base, depth + 1, out)?;
+            self.stack.pop();
+            out.push('\n');
+            crate::log::vinfo(&format!(
+                "Leaving import {} (depth {})",
+                canonical.display(),
+                depth + 1
+            ));
+        }
+
+        out.push_str(&code[last_end..]);
+
+        Ok(())
+    }
+
+    /// Append `const string NAME = "...";` holding the imported file's text.
+    /// Text files may be embedded any number of times, so they aren't
+    /// tracked as visited.
+    fn inline_text(
+        &mut self,
+        import: &Import,
+        alias: &str,
+        base_path: &Path,
+        out: &mut String,
+    ) -> Result<()> {
+        let canonical = match resolve_file(
+            &import.path,
+            &import.path,
+            base_path,
+            &self.options.include_dirs,
+        ) {
+            Ok(canonical) => canonical,
+            Err(ErsaError::ImportNotFound { path, .. }) if import.optional => {
+                self.warnings.push(format!(
+                    "Skipping optional import '{}': file not found",
+                    path
+                ));
+                return Ok(());
+            }
+            Err(e) => return self.recover(e, import, out),
+        };
+        self.assets.insert(canonical.clone());
+
+        match fs::read_to_string(&canonical) {
+            Ok(contents) => {
+                let contents = super::strip_bom(&contents);
+                out.push_str(&format!(
+                    "const string {} = {};",
+                    alias,
+                    string_literal(contents)
+                ));
+                Ok(())
+            }
+            Err(e) => {
+                let context = format!("Failed to read imported file '{}'", canonical.display());
+                self.recover(ErsaError::io(context)(e), import, out)
+            }
+        }
+    }
+
+    /// Record a failed import and leave a comment in its place under
+    /// `keep_going`, otherwise return the error
+    fn recover(&mut self, err: ErsaError, import: &Import, out: &mut String) -> Result<()> {
+        if !self.options.keep_going {
+            return Err(err);
+        }
+
+        out.push_str(&format!("// import '{}' failed", import.path));
+        self.errors.push(err);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::diagnostics::Severity;
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ersa_imports_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_missing_import_error() {
+        let dir = temp_dir("missing");
+        let err = process_imports(
+            "import nope;",
+            &dir,
+            &mut HashSet::new(),
+            &Default::default(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ErsaError::ImportNotFound { ref path, .. } if path == "nope"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-    let mut result = String::new();
-    let mut last_end = 0;
+    #[test]
+    fn test_lexical_error_in_import() {
+        let dir = temp_dir("lexical");
+        fs::write(dir.join("strings.gpc"), "int a;\nstring s = \"oops;\n").unwrap();
+        fs::write(dir.join("braces.gpc"), "function f() {\n    x = 1;\n").unwrap();
 
-    for cap in re.captures_iter(code) {
-        let match_pos = cap.get(0).unwrap();
+        for (name, line, column, message) in [
+            ("strings", 2, 12, "Unterminated string literal"),
+            ("braces", 1, 14, "Unclosed '{'"),
+        ] {
+            let err = process_imports(
+                &format!("main {{ }}\nimport {};", name),
+                &dir,
+                &mut HashSet::new(),
+                &Default::default(),
+                &mut Vec::new(),
+            )
+            .unwrap_err();
+            let file = fs::canonicalize(dir.join(format!("{}.gpc", name))).unwrap();
+            assert!(
+                matches!(err, ErsaError::Lexical { file: ref f, line: l, column: c, message: ref m }
+                    if *f == file && l == line && c == column && m == message),
+                "{:?}",
+                err
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_keep_going_reports_all_missing_imports() {
+        let dir = temp_dir("keep_going");
+        fs::write(dir.join("found.gpc"), "int found;").unwrap();
+        let code = "import first_missing;\nimport found;\nimport second_missing;\nint a;";
+
+        let err = process_imports(
+            code,
+            &dir,
+            &mut HashSet::new(),
+            &Default::default(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, ErsaError::ImportNotFound { ref path, .. } if path == "first_missing")
+        );
+
+        let options = PreprocessOptions {
+            keep_going: true,
+            ..Default::default()
+        };
+        let err = process_imports(code, &dir, &mut HashSet::new(), &options, &mut Vec::new())
+            .unwrap_err();
+        let ErsaError::Imports(errors) = &err else {
+            panic!("expected Imports, got {:?}", err);
+        };
+        assert_eq!(errors.len(), 2);
+        let message = err.to_string();
+        assert!(message.contains("first_missing"));
+        assert!(message.contains("second_missing"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_dirs() {
+        let dir = temp_dir("include");
+        let shared = temp_dir("include_shared");
+        let other = temp_dir("include_other");
+        fs::write(shared.join("common.gpc"), "int common;").unwrap();
+
+        let options = PreprocessOptions {
+            include_dirs: vec![other.clone(), shared.clone()],
+            ..Default::default()
+        };
+        let result = process_imports(
+            "import common;",
+            &dir,
+            &mut HashSet::new(),
+            &options,
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert!(result.contains("int common;"));
+
+        let err = resolve_import("missing", &dir, &options.include_dirs).unwrap_err();
+        let ErsaError::ImportNotFound { ref tried, .. } = err else {
+            panic!("expected ImportNotFound, got {:?}", err);
+        };
+        assert_eq!(
+            tried,
+            &[
+                dir.join("missing.gpc"),
+                other.join("missing.gpc"),
+                shared.join("missing.gpc")
+            ]
+        );
+        assert!(err.to_string().contains("tried"));
+
+        for d in [dir, shared, other] {
+            fs::remove_dir_all(d).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_print_imports() {
+        let _lock = crate::log::TEST_LOCK.lock().unwrap();
+        let dir = temp_dir("print");
+        fs::write(dir.join("a.gpc"), "import b;\nint a;").unwrap();
+        fs::write(dir.join("b.gpc"), "int b;").unwrap();
+        fs::write(dir.join("c.gpc"), "int c;").unwrap();
+
+        let options = PreprocessOptions {
+            print_imports: true,
+            ..Default::default()
+        };
+        let (result, lines) = crate::log::capture(|| {
+            process_imports(
+                "import a;\nimport c;",
+                &dir,
+                &mut HashSet::new(),
+                &options,
+                &mut Vec::new(),
+            )
+        });
+        result.unwrap();
+
+        let expected: Vec<String> = ["a", "b", "c"]
+            .iter()
+            .map(|f| {
+                dir.join(format!("{}.gpc", f))
+                    .canonicalize()
+                    .unwrap()
+                    .display()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(lines, expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_circular_import_error() {
+        let dir = temp_dir("circular");
+        fs::write(dir.join("a.gpc"), "import b;").unwrap();
+        fs::write(dir.join("b.gpc"), "import a;").unwrap();
 
-        result.push_str(&code[last_end..match_pos.start()]);
+        let err = process_imports(
+            "import a;",
+            &dir,
+            &mut HashSet::new(),
+            &Default::default(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ErsaError::CircularImport(_)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verbose_import_depth_logging() {
+        let _lock = crate::log::TEST_LOCK.lock().unwrap();
+        let dir = temp_dir("depth");
+        fs::write(dir.join("a.gpc"), "import b;").unwrap();
+        fs::write(dir.join("b.gpc"), "int b;").unwrap();
 
-        let path_str = cap.get(1).or(cap.get(2)).unwrap().as_str();
+        crate::log::set_verbose(true);
+        let (result, lines) = crate::log::capture(|| {
+            process_imports(
+                "import a;",
+                &dir,
+                &mut HashSet::new(),
+                &Default::default(),
+                &mut Vec::new(),
+            )
+        });
+        crate::log::set_verbose(false);
+        result.unwrap();
 
-        let path_with_ext = if path_str.ends_with(".gpc") {
-            path_str.to_string()
-        } else {
-            format!("{}.gpc", path_str)
+        let events: Vec<_> = lines
+            .iter()
+            .map(|l| {
+                let word = if l.contains("Entering") {
+                    "enter"
+                } else {
+                    "leave"
+                };
+                let file = if l.contains("a.gpc") { "a" } else { "b" };
+                let depth = l.rsplit("depth ").next().unwrap().trim_end_matches(')');
+                format!("{} {} {}", word, file, depth)
+            })
+            .collect();
+        assert_eq!(events, ["enter a 1", "enter b 2", "leave b 2", "leave a 1"]);
+
+        let (_, lines) = crate::log::capture(|| {
+            process_imports(
+                "import a;",
+                &dir,
+                &mut HashSet::new(),
+                &Default::default(),
+                &mut Vec::new(),
+            )
+        });
+        assert!(lines.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_once() {
+        let dir = temp_dir("once");
+        fs::write(dir.join("common.gpc"), "int shared;").unwrap();
+        fs::write(dir.join("a.gpc"), "import common @once;\nint a;").unwrap();
+
+        let code = "import common @once;\nimport a;";
+        let result = process_imports(
+            code,
+            &dir,
+            &mut HashSet::new(),
+            &Default::default(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(result.matches("int shared;").count(), 1);
+        assert!(result.contains("int a;"));
+
+        let options = PreprocessOptions {
+            reinline_imports: true,
+            ..Default::default()
         };
+        let result =
+            process_imports(code, &dir, &mut HashSet::new(), &options, &mut Vec::new()).unwrap();
+        assert_eq!(result.matches("int shared;").count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_optional() {
+        let dir = temp_dir("optional");
+        let mut warnings = Vec::new();
+
+        let result = process_imports(
+            "import \"local_settings\" @optional;\nint a;",
+            &dir,
+            &mut HashSet::new(),
+            &Default::default(),
+            &mut warnings,
+        )
+        .unwrap();
+        assert_eq!(result, "\nint a;");
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("local_settings"));
+
+        let err = find_imports("import x @sometimes;").unwrap_err();
+        assert!(err.to_string().contains("@sometimes"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-        let full_path = base_path.join(&path_with_ext);
-        let canonical = full_path.canonicalize().map_err(|e| {
-            format!(
-                "Failed to resolve import path '{}' (resolved to '{}'): {}",
-                path_str,
-                full_path.display(),
-                e
+    /// A chain of `DEPTH` files, each importing the next and adding `LINES` lines
+    fn import_chain(name: &str, depth: usize, lines: usize) -> PathBuf {
+        let dir = temp_dir(name);
+        let filler = "int value = 0;\n".repeat(lines);
+        for i in 0..depth {
+            let next = if i + 1 < depth {
+                format!("import m{};\n", i + 1)
+            } else {
+                String::new()
+            };
+            fs::write(
+                dir.join(format!("m{}.gpc", i)),
+                format!("{}{}", next, filler),
             )
-        })?;
+            .unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_large_import_chain() {
+        const DEPTH: usize = 300;
+        let dir = import_chain("large", DEPTH, 200);
+
+        let result = process_imports(
+            "import m0;",
+            &dir,
+            &mut HashSet::new(),
+            &Default::default(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(result.matches("int value = 0;").count(), DEPTH * 200);
+        assert!(!result.contains("import"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-        if visited.contains(&canonical) {
-            return Err(format!("Circular import detected: {}", canonical.display()));
+    /// The inliner as it was before it wrote into one shared buffer: every
+    /// file builds its own string, which its importer then copies, so a file
+    /// `n` levels deep is copied `n` times
+    fn inline_by_copying(code: &str, base: &Path) -> String {
+        let mut result = String::new();
+        let mut last_end = 0;
+        for import in find_imports(code).unwrap() {
+            result.push_str(&code[last_end..import.span.start]);
+            let path = resolve_import(&import.path, base, &[]).unwrap();
+            let imported = fs::read_to_string(&path).unwrap();
+            result.push_str(&inline_by_copying(&imported, path.parent().unwrap()));
+            result.push('\n');
+            last_end = import.span.end;
         }
-        visited.insert(canonical.clone());
+        result.push_str(&code[last_end..]);
+        result
+    }
 
-        let imported_code = fs::read_to_string(&canonical).map_err(|e| {
-            format!(
-                "Failed to read imported file '{}': {}",
-                canonical.display(),
-                e
+    /// Times the inliner against [`inline_by_copying`] on a deep chain and
+    /// prints both. The inliner also validates and scans every file, so the
+    /// times are reported rather than asserted; memory isn't measured. Run
+    /// with `cargo test import_chain_timing -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "timing comparison, run on demand"]
+    fn test_import_chain_timing() {
+        const DEPTH: usize = 300;
+        let dir = import_chain("timing", DEPTH, 200);
+        let time = |f: &dyn Fn() -> String| {
+            let start = std::time::Instant::now();
+            let output = f();
+            (output, start.elapsed())
+        };
+
+        let (copied, copying) = time(&|| inline_by_copying("import m0;", &dir));
+        let (streamed, streaming) = time(&|| {
+            process_imports(
+                "import m0;",
+                &dir,
+                &mut HashSet::new(),
+                &Default::default(),
+                &mut Vec::new(),
             )
-        })?;
+            .unwrap()
+        });
+        println!(
+            "{} files, {} bytes: copying {:?}, shared buffer {:?}",
+            DEPTH,
+            streamed.len(),
+            copying,
+            streaming
+        );
 
-        let imported_base = canonical
-            .parent()
-            .ok_or_else(|| format!("Failed to get parent directory of {}", canonical.display()))?;
-        let processed_import = process_imports(&imported_code, imported_base, visited)?;
+        assert_eq!(
+            streamed.matches("int value = 0;").count(),
+            copied.matches("int value = 0;").count()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-        result.push_str(&processed_import);
-        result.push('\n');
+    #[test]
+    fn test_diagnostic_spans() {
+        let dir = temp_dir("diagnostics");
+        fs::write(dir.join("good.gpc"), "int a;").unwrap();
+        fs::write(dir.join("broken.gpc"), "import nowhere;").unwrap();
+        let code = "import good;\nimport missing;\nimport broken;\nimport gone @optional;\n";
 
-        last_end = match_pos.end();
+        let found = diagnostics(code, &dir, &PreprocessOptions::default());
+        assert_eq!(found.len(), 3);
+
+        assert_eq!(found[0].severity, Severity::Error);
+        assert_eq!(&code[found[0].span.clone()], "import missing;");
+        assert!(found[0].message.contains("missing"));
+
+        assert_eq!(&code[found[1].span.clone()], "import broken;");
+        assert!(found[1].message.contains("nowhere"));
+
+        assert_eq!(found[2].severity, Severity::Warning);
+        let optional = "import gone @optional;";
+        let start = code.find(optional).unwrap();
+        assert_eq!(found[2].span, start..start + optional.len());
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    result.push_str(&code[last_end..]);
+    #[test]
+    fn test_max_imports() {
+        let dir = temp_dir("max");
+        for name in ["a", "b", "c"] {
+            fs::write(dir.join(format!("{}.gpc", name)), "int x;").unwrap();
+        }
+        let code = "import a;\nimport b;\nimport c;\n";
 
-    Ok(result)
+        let options = PreprocessOptions {
+            max_imports: Some(2),
+            keep_going: true,
+            ..Default::default()
+        };
+        let result = process_imports(code, &dir, &mut HashSet::new(), &options, &mut Vec::new());
+        assert!(matches!(result, Err(ErsaError::TooManyImports(2))));
+
+        let options = PreprocessOptions {
+            max_imports: Some(3),
+            ..Default::default()
+        };
+        assert!(
+            process_imports(code, &dir, &mut HashSet::new(), &options, &mut Vec::new()).is_ok()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_text_asset() {
+        let dir = temp_dir("text");
+        fs::create_dir_all(dir.join("data")).unwrap();
+        fs::write(dir.join("data/table.txt"), "a\t1\n\"b\" \\ 2\n").unwrap();
+        let code = "import text \"data/table.txt\" as TABLE;\nmain { }\n";
+
+        let output = process_imports(
+            code,
+            &dir,
+            &mut HashSet::new(),
+            &PreprocessOptions::default(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            "const string TABLE = \"a\\t1\\n\\\"b\\\" \\\\ 2\\n\";\nmain { }\n"
+        );
+
+        let imports = find_imports("import text;\nimport text \"x.txt\" as X;").unwrap();
+        assert_eq!(imports[0].path, "text");
+        assert_eq!(imports[0].text_alias, None);
+        assert_eq!(imports[1].path, "x.txt");
+        assert_eq!(imports[1].text_alias.as_deref(), Some("X"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_diamond_is_not_a_cycle() {
+        let dir = temp_dir("diamond");
+        fs::write(dir.join("a.gpc"), "import c;\nint a;").unwrap();
+        fs::write(dir.join("b.gpc"), "import c;\nint b;").unwrap();
+        fs::write(dir.join("c.gpc"), "int c;\nfunction c() {}").unwrap();
+        let code = "import a;\nimport b;\n";
+
+        let mut warnings = Vec::new();
+        let output = process_imports(
+            code,
+            &dir,
+            &mut HashSet::new(),
+            &PreprocessOptions::default(),
+            &mut warnings,
+        )
+        .unwrap();
+        assert_eq!(output.matches("int c;").count(), 1);
+        assert!(warnings.is_empty());
+        assert!(output.contains("int a;") && output.contains("int b;"));
+
+        let options = PreprocessOptions {
+            reinline_imports: true,
+            ..Default::default()
+        };
+        let output =
+            process_imports(code, &dir, &mut HashSet::new(), &options, &mut Vec::new()).unwrap();
+        assert_eq!(output.matches("int c;").count(), 2);
+
+        // c -> a closes a real cycle in both modes
+        fs::write(dir.join("c.gpc"), "import a;\nint c;").unwrap();
+        for options in [PreprocessOptions::default(), options] {
+            let err = process_imports(code, &dir, &mut HashSet::new(), &options, &mut Vec::new())
+                .unwrap_err();
+            assert!(matches!(err, ErsaError::CircularImport(_)));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_definitions_across_imports() {
+        let dir = temp_dir("duplicates");
+        fs::write(dir.join("a.gpc"), "function foo() {\n    return 1;\n}\n").unwrap();
+        fs::write(dir.join("b.gpc"), "int b;\n\nfunction foo() {}\n").unwrap();
+
+        let mut warnings = Vec::new();
+        process_imports(
+            "import a;\nimport b;\nmain {}\n",
+            &dir,
+            &mut HashSet::new(),
+            &PreprocessOptions::default(),
+            &mut warnings,
+        )
+        .unwrap();
+        let a = dir.join("a.gpc").canonicalize().unwrap();
+        let b = dir.join("b.gpc").canonicalize().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            warnings,
+            [format!(
+                "Function 'foo' is defined at {}:1 and again at {}:3",
+                a.display(),
+                b.display()
+            )]
+        );
+    }
 }
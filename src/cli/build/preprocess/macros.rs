@@ -1,4 +1,10 @@
-use std::collections::HashMap;
+use super::PreprocessOptions;
+use super::diagnostics::Diagnostic;
+use super::scan::comment_len;
+use crate::error::{ErsaError, Result};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::Path;
 
 #[derive(Debug, Clone)]
 pub struct MacroDefinition {
@@ -6,19 +12,193 @@ pub struct MacroDefinition {
     pub params: Vec<String>,
     pub body: String,
     pub requires_body: bool,
+    /// Defined as `define! NAME = value;` and expanded on every bare use of `NAME`
+    pub object_like: bool,
 }
 
-pub fn process_macros(code: &str) -> Result<String, String> {
-    let (code_without_defs, macros) = extract_macro_definitions(code)?;
-    let expanded = expand_macros(&code_without_defs, &macros)?;
+/// Expand the macros defined in `code`, plus any defines given on the command line.
+///
+/// With `warn_unused_macros`, macros defined in `code` but never expanded are
+/// reported in `warnings`.
+pub fn process_macros(
+    code: &str,
+    options: &PreprocessOptions,
+    warnings: &mut Vec<String>,
+) -> Result<String> {
+    let mut expanded = expand_all(code, options)?;
+    warnings.append(&mut expanded.warnings);
+    if options.warn_unused_macros {
+        for name in expanded.unused {
+            warnings.push(format!("Macro '{}' is defined but never used", name));
+        }
+    }
+
+    Ok(expanded.code)
+}
 
-    Ok(expanded)
+/// Result of running the macro pass over one source
+struct Expanded {
+    code: String,
+    /// Macros defined in the source but never expanded, sorted by name
+    unused: Vec<String>,
+    warnings: Vec<String>,
 }
 
+fn expand_all(code: &str, options: &PreprocessOptions) -> Result<Expanded> {
+    let (code_without_defs, mut macros) =
+        extract_macro_definitions(code, options.keep_macro_comments, options.max_macro_body)?;
+
+    let mut unused: Vec<String> = macros.keys().cloned().collect();
+
+    for define in &options.defines {
+        if macros.contains_key(&define.name) {
+            return Err(ErsaError::DuplicateMacro(define.name.clone()));
+        }
+        macros.insert(define.name.clone(), define.clone());
+    }
+
+    let mut expansion = Expansion {
+        strict: options.strict_macros,
+        sequential: options.sequential_params,
+        trace: options.trace_macros,
+        ..Default::default()
+    };
+    let expanded = expand_macros(&code_without_defs, &macros, &mut expansion)?;
+
+    unused.retain(|name| !expansion.used.contains(name));
+    unused.sort();
+
+    Ok(Expanded {
+        code: expanded,
+        unused,
+        warnings: expansion.warnings,
+    })
+}
+
+/// Diagnose the macros in `code`, the entry file before imports were inlined.
+///
+/// Macros are looked up in `inlined`, the same source with its imports
+/// inlined, so those defined in imported files are known. Spans are offsets
+/// into `code`; problems that can't be placed there get an empty span at 0.
+pub fn diagnostics(code: &str, inlined: &str, options: &PreprocessOptions) -> Vec<Diagnostic> {
+    let spans = match scan_definitions(code, false, options.max_macro_body) {
+        Ok(scan) => scan.spans,
+        Err((err, span)) => return vec![Diagnostic::error(span, err.to_string())],
+    };
+
+    let expanded = match expand_all(inlined, options) {
+        Ok(expanded) => expanded,
+        Err(err) => {
+            let span = match &err {
+                ErsaError::UndefinedMacro(name) | ErsaError::MacroArity { name, .. } => {
+                    find_call(code, name)
+                }
+                ErsaError::DuplicateMacro(name) => spans.get(name).cloned(),
+                _ => None,
+            };
+            return vec![Diagnostic::error(span.unwrap_or(0..0), err.to_string())];
+        }
+    };
+
+    let mut diagnostics: Vec<Diagnostic> = expanded
+        .warnings
+        .into_iter()
+        .map(|warning| Diagnostic::warning(0..0, warning))
+        .collect();
+    for name in expanded.unused {
+        let span = spans.get(&name).cloned().unwrap_or(0..0);
+        diagnostics.push(Diagnostic::warning(
+            span,
+            format!("Macro '{}' is defined but never used", name),
+        ));
+    }
+    diagnostics
+}
+
+/// Span of the first `name!` or `name(args)!` call in `code`
+fn find_call(code: &str, name: &str) -> Option<Range<usize>> {
+    let pattern = format!(r"\b{}\s*(?:\([^)]*\))?\s*!", regex::escape(name));
+    let re = regex::Regex::new(&pattern).ok()?;
+    re.find_iter(code)
+        .find(|m| !code[m.end()..].starts_with('='))
+        .map(|m| m.range())
+}
+
+/// Parse a `NAME` or `NAME=VALUE` command-line define into a macro with no parameters
+pub fn parse_define(define: &str) -> Result<MacroDefinition> {
+    let (name, body) = match define.split_once('=') {
+        Some((name, value)) => (name.trim(), value.trim()),
+        None => (define.trim(), ""),
+    };
+
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(ErsaError::MacroSyntax(format!(
+            "Invalid macro name in define '{}'",
+            define
+        )));
+    }
+
+    Ok(MacroDefinition {
+        name: name.to_string(),
+        params: Vec::new(),
+        body: body.to_string(),
+        requires_body: false,
+        object_like: false,
+    })
+}
+
+/// Parse a defines file: one `NAME` or `NAME=VALUE` per line, as with `-D`.
+///
+/// Blank lines and lines starting with `#` or `//` are skipped. Errors name
+/// `path` and the offending line.
+pub fn parse_defines_file(contents: &str, path: &Path) -> Result<Vec<MacroDefinition>> {
+    let mut defines = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+            continue;
+        }
+        let define = parse_define(line).map_err(|e| {
+            ErsaError::MacroSyntax(format!("{}:{}: {}", path.display(), index + 1, e))
+        })?;
+        defines.push(define);
+    }
+    Ok(defines)
+}
+
+/// Remove every `define!` block from `code` and collect the macros they define.
+///
+/// With `keep_comments`, each block is replaced by a `// macro NAME(params)`
+/// comment where it stood instead of being dropped. Bodies longer than
+/// `max_body` bytes are rejected.
 pub fn extract_macro_definitions(
     code: &str,
-) -> Result<(String, HashMap<String, MacroDefinition>), String> {
+    keep_comments: bool,
+    max_body: Option<usize>,
+) -> Result<(String, HashMap<String, MacroDefinition>)> {
+    scan_definitions(code, keep_comments, max_body)
+        .map(|scan| (scan.code, scan.macros))
+        .map_err(|(err, _)| err)
+}
+
+/// Source with its definitions removed, and what they defined
+struct Definitions {
+    code: String,
+    macros: HashMap<String, MacroDefinition>,
+    /// Byte span of each definition, by macro name
+    spans: HashMap<String, Range<usize>>,
+}
+
+/// [`extract_macro_definitions`], also returning where each definition is.
+/// On error, the span runs from the failed definition's `define!` to where
+/// parsing stopped.
+fn scan_definitions(
+    code: &str,
+    keep_comments: bool,
+    max_body: Option<usize>,
+) -> std::result::Result<Definitions, (ErsaError, Range<usize>)> {
     let mut macros = HashMap::new();
+    let mut spans = HashMap::new();
     let mut result = String::new();
     let mut chars = code.chars().peekable();
     let mut pos = 0;
@@ -27,62 +207,135 @@ pub fn extract_macro_definitions(
         pos += ch.len_utf8();
 
         if ch == 'd' && code[pos - 1..].starts_with("define!") {
-            for _ in 0..6 {
-                chars.next();
-                pos += 1;
+            let start = pos - 1;
+            let definition = parse_definition(&mut chars, &mut pos, code, max_body)
+                .map_err(|err| (err, start..pos))?;
+
+            if keep_comments {
+                result.push_str(&definition_comment(&definition.name, &definition.params));
             }
 
-            skip_whitespace(&mut chars, &mut pos);
+            spans.insert(definition.name.clone(), start..pos);
+            macros.insert(definition.name.clone(), definition);
+        } else {
+            result.push(ch);
+        }
+    }
 
-            let name = extract_identifier(&mut chars, &mut pos)?;
-            if name.is_empty() {
-                return Err("Macro definition missing name after 'define!'".to_string());
-            }
+    Ok(Definitions {
+        code: result,
+        macros,
+        spans,
+    })
+}
 
-            skip_whitespace(&mut chars, &mut pos);
+/// Parse one definition whose leading `d` of `define!` was just consumed
+fn parse_definition<I>(
+    chars: &mut std::iter::Peekable<I>,
+    pos: &mut usize,
+    code: &str,
+    max_body: Option<usize>,
+) -> Result<MacroDefinition>
+where
+    I: Iterator<Item = char>,
+{
+    for _ in 0..6 {
+        chars.next();
+        *pos += 1;
+    }
 
-            let params = if chars.peek() == Some(&'(') {
-                chars.next();
-                pos += 1;
-                extract_parameters(&mut chars, &mut pos)?
-            } else {
-                Vec::new()
-            };
+    skip_whitespace(chars, pos);
 
-            skip_whitespace(&mut chars, &mut pos);
+    let name = extract_identifier(chars, pos)?;
+    if name.is_empty() {
+        return Err(ErsaError::MacroSyntax(
+            "Macro definition missing name after 'define!'".to_string(),
+        ));
+    }
 
-            if chars.peek() != Some(&'{') {
-                return Err(format!("Expected '{{' after macro definition '{}'", name));
-            }
+    skip_whitespace(chars, pos);
+
+    // `define! NAME = value;` is object-like and expands without `!`
+    let object_like = chars.peek() == Some(&'=');
+    let (params, body) = if object_like {
+        chars.next();
+        *pos += 1;
+        (Vec::new(), extract_object_body(chars, pos, &name)?)
+    } else {
+        let params = if chars.peek() == Some(&'(') {
             chars.next();
-            pos += 1;
-
-            let body = extract_balanced_braces(&mut chars, &mut pos)?;
-
-            let trimmed_body = body.trim().to_string();
-            let requires_body = trimmed_body.contains("%0");
-
-            macros.insert(
-                name.clone(),
-                MacroDefinition {
-                    name,
-                    params,
-                    body: trimmed_body,
-                    requires_body,
-                },
-            );
+            *pos += 1;
+            extract_parameters(chars, pos)?
         } else {
-            result.push(ch);
+            Vec::new()
+        };
+
+        skip_whitespace(chars, pos);
+
+        if chars.peek() != Some(&'{') {
+            return Err(ErsaError::MacroSyntax(format!(
+                "Expected '{{' after macro definition '{}'",
+                name
+            )));
+        }
+        chars.next();
+        *pos += 1;
+
+        let body = extract_balanced_braces(chars, pos, code, max_body)?;
+        (params, body)
+    };
+
+    let body = body.trim().to_string();
+    let requires_body = body.contains("%0");
+
+    Ok(MacroDefinition {
+        name,
+        params,
+        body,
+        requires_body,
+        object_like,
+    })
+}
+
+/// Read the value of an object-like macro up to its terminating `;`
+fn extract_object_body<I>(
+    chars: &mut std::iter::Peekable<I>,
+    pos: &mut usize,
+    name: &str,
+) -> Result<String>
+where
+    I: Iterator<Item = char>,
+{
+    let mut body = String::new();
+    for ch in chars.by_ref() {
+        *pos += ch.len_utf8();
+        if ch == ';' {
+            return Ok(body);
         }
+        body.push(ch);
     }
 
-    Ok((result, macros))
+    Err(ErsaError::MacroSyntax(format!(
+        "Expected ';' after object-like macro '{}'",
+        name
+    )))
 }
 
+/// Comment left in place of a removed definition
+fn definition_comment(name: &str, params: &[String]) -> String {
+    if params.is_empty() {
+        format!("// macro {}", name)
+    } else {
+        format!("// macro {}({})", name, params.join(", "))
+    }
+}
+
+/// Read a definition's parameter names up to the closing `)`. A trailing
+/// comma is allowed.
 pub fn extract_parameters<I>(
     chars: &mut std::iter::Peekable<I>,
     pos: &mut usize,
-) -> Result<Vec<String>, String>
+) -> Result<Vec<String>>
 where
     I: Iterator<Item = char>,
 {
@@ -116,10 +369,15 @@ where
                 *pos += ch.len_utf8();
             }
             Some(&ch) => {
-                return Err(format!("Unexpected character '{}' in parameter list", ch));
+                return Err(ErsaError::MacroSyntax(format!(
+                    "Unexpected character '{}' in parameter list",
+                    ch
+                )));
             }
             None => {
-                return Err("Unexpected end of input in parameter list".to_string());
+                return Err(ErsaError::MacroSyntax(
+                    "Unexpected end of input in parameter list".to_string(),
+                ));
             }
         }
     }
@@ -127,10 +385,7 @@ where
     Ok(params)
 }
 
-pub fn extract_identifier<I>(
-    chars: &mut std::iter::Peekable<I>,
-    pos: &mut usize,
-) -> Result<String, String>
+pub fn extract_identifier<I>(chars: &mut std::iter::Peekable<I>, pos: &mut usize) -> Result<String>
 where
     I: Iterator<Item = char>,
 {
@@ -161,17 +416,24 @@ where
     }
 }
 
+/// Read up to the `}` matching the `{` just consumed from `source`.
+///
+/// Errors name the line and column of the opening brace, both when it is
+/// never closed and when the body grows past `limit` bytes.
 pub fn extract_balanced_braces<I>(
     chars: &mut std::iter::Peekable<I>,
     pos: &mut usize,
-) -> Result<String, String>
+    source: &str,
+    limit: Option<usize>,
+) -> Result<String>
 where
     I: Iterator<Item = char>,
 {
+    let open = *pos - 1;
     let mut body = String::new();
     let mut depth = 1;
 
-    while let Some(ch) = chars.next() {
+    for ch in chars.by_ref() {
         *pos += ch.len_utf8();
 
         match ch {
@@ -190,15 +452,111 @@ where
                 body.push(ch);
             }
         }
+
+        if let Some(limit) = limit
+            && body.len() > limit
+        {
+            let (line, column) = line_col(source, open);
+            return Err(ErsaError::MacroSyntax(format!(
+                "Macro body opened at line {}, column {} is longer than {} bytes",
+                line, column, limit
+            )));
+        }
     }
 
-    Err("Unmatched braces in macro definition".to_string())
+    let (line, column) = line_col(source, open);
+    Err(ErsaError::MacroSyntax(format!(
+        "Unmatched '{{' opened at line {}, column {}",
+        line, column
+    )))
+}
+
+/// 1-based line and column of byte `offset` in `source`
+fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    (line, column)
 }
 
+/// State carried through one expansion, including nested re-expansion
+#[derive(Debug, Default)]
+pub struct Expansion {
+    /// Names of the macros expanded so far
+    pub used: HashSet<String>,
+    /// Warn about macro names that appear without `!`
+    pub strict: bool,
+    /// Substitute parameters one at a time, see [`substitute_macro`]
+    pub sequential: bool,
+    /// Object-like macros being expanded, which are left alone inside
+    /// their own expansion
+    pub active: Vec<String>,
+    /// Print each expansion to stderr as it happens
+    pub trace: bool,
+    pub warnings: Vec<String>,
+}
+
+impl Expansion {
+    /// Note an identifier that wasn't invoked as a macro
+    fn plain_identifier(&mut self, name: &str, macros: &HashMap<String, MacroDefinition>) {
+        if self.strict && macros.contains_key(name) {
+            self.warnings.push(format!(
+                "'{}' is the name of a macro but is used without '!'",
+                name
+            ));
+        }
+    }
+
+    /// Print one expansion for `--trace-macros`, indented by nesting depth
+    fn trace(&self, depth: usize, call: &str, expanded: &str) {
+        if !self.trace {
+            return;
+        }
+        crate::log::plain_err(&format!(
+            "{}{} => {}",
+            "  ".repeat(depth),
+            call,
+            trace_text(expanded)
+        ));
+    }
+}
+
+/// Longest expansion shown in full by `--trace-macros`
+const TRACE_WIDTH: usize = 60;
+
+/// `text` on one line, cut to [`TRACE_WIDTH`] characters
+fn trace_text(text: &str) -> String {
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() <= TRACE_WIDTH {
+        return line;
+    }
+    let mut cut: String = line.chars().take(TRACE_WIDTH).collect();
+    cut.push_str("...");
+    cut
+}
+
+/// Whether the text after an identifier makes it a `NAME!` call
+fn invoked_with_bang(rest: &str) -> bool {
+    let rest = rest.trim_start();
+    rest.starts_with('!') && !rest.starts_with("!=")
+}
+
+/// Expand every macro call in `code`
 pub fn expand_macros(
     code: &str,
     macros: &HashMap<String, MacroDefinition>,
-) -> Result<String, String> {
+    expansion: &mut Expansion,
+) -> Result<String> {
+    expand_nested(code, macros, expansion, 0)
+}
+
+/// [`expand_macros`] for text produced by `depth` enclosing expansions
+fn expand_nested(
+    code: &str,
+    macros: &HashMap<String, MacroDefinition>,
+    expansion: &mut Expansion,
+    depth: usize,
+) -> Result<String> {
     let mut result = String::new();
     let mut chars = code.chars().peekable();
     let mut pos = 0;
@@ -206,7 +564,21 @@ pub fn expand_macros(
     while let Some(ch) = chars.next() {
         pos += ch.len_utf8();
 
-        if ch.is_alphabetic() || ch == '_' {
+        if ch == '"' {
+            // Nothing inside a string literal is a macro call
+            result.push(ch);
+            copy_string_literal(&mut chars, &mut pos, &mut result);
+        } else if ch == '/'
+            && let Some(len) = comment_len(&code[pos - 1..])
+        {
+            // Nor is anything inside a comment
+            let comment = &code[pos - 1..pos - 1 + len];
+            result.push_str(comment);
+            for _ in comment[1..].chars() {
+                chars.next();
+            }
+            pos += len - 1;
+        } else if ch.is_alphabetic() || ch == '_' {
             let mut name = String::new();
             name.push(ch);
 
@@ -220,7 +592,22 @@ pub fn expand_macros(
                 }
             }
 
-            skip_whitespace(&mut chars, &mut pos);
+            if let Some(macro_def) = macros.get(&name)
+                && macro_def.object_like
+                && !expansion.active.contains(&name)
+                && !invoked_with_bang(&code[pos..])
+            {
+                expansion.used.insert(name.clone());
+                expansion.trace(depth, &name, &macro_def.body);
+                expansion.active.push(name);
+                let expanded = expand_nested(&macro_def.body, macros, expansion, depth + 1)?;
+                expansion.active.pop();
+                result.push_str(&expanded);
+                continue;
+            }
+
+            // Whitespace is only dropped if this turns out to be a macro call
+            let space_before_args = take_whitespace(&mut chars, &mut pos);
 
             let args = if chars.peek() == Some(&'(') {
                 chars.next();
@@ -230,50 +617,81 @@ pub fn expand_macros(
                 None
             };
 
-            skip_whitespace(&mut chars, &mut pos);
+            let space_after_args = take_whitespace(&mut chars, &mut pos);
 
             if chars.peek() == Some(&'!') {
                 chars.next();
                 pos += 1;
 
-                skip_whitespace(&mut chars, &mut pos);
+                // `name != value` is a comparison, not a macro call
+                if chars.peek() == Some(&'=') {
+                    expansion.plain_identifier(&name, macros);
+                    result.push_str(&name);
+                    result.push_str(&space_before_args);
+                    if let Some(ref arg_list) = args {
+                        result.push('(');
+                        result.push_str(&expand_nested(arg_list, macros, expansion, depth)?);
+                        result.push(')');
+                        result.push_str(&space_after_args);
+                    }
+                    result.push('!');
+                    continue;
+                }
+
+                let space_before_body = take_whitespace(&mut chars, &mut pos);
 
                 if let Some(macro_def) = macros.get(&name) {
+                    expansion.used.insert(name.clone());
                     let body = if macro_def.requires_body {
                         if chars.peek() != Some(&'{') {
-                            return Err(format!(
+                            return Err(ErsaError::MacroSyntax(format!(
                                 "Expected '{{' after macro call '{}!' (macro requires a body)",
                                 name
-                            ));
+                            )));
                         }
                         chars.next();
                         pos += 1;
-                        extract_balanced_braces(&mut chars, &mut pos)?
+                        Some(extract_balanced_braces(&mut chars, &mut pos, code, None)?)
+                    } else if chars.peek() == Some(&'{') {
+                        chars.next();
+                        pos += 1;
+                        Some(extract_balanced_braces(&mut chars, &mut pos, code, None)?)
                     } else {
-                        if chars.peek() == Some(&'{') {
-                            chars.next();
-                            pos += 1;
-                            extract_balanced_braces(&mut chars, &mut pos)?
-                        } else {
-                            String::new()
-                        }
+                        None
                     };
 
-                    let expanded = substitute_macro(macro_def, args.as_deref(), &body)?;
+                    let expanded = substitute_macro(
+                        macro_def,
+                        args.as_deref(),
+                        body.as_deref().unwrap_or(""),
+                        expansion.sequential,
+                    )?;
+
+                    let call = match &args {
+                        Some(arg_list) => format!("{}({})", name, arg_list.trim()),
+                        None => name.clone(),
+                    };
+                    expansion.trace(depth, &call, &expanded);
 
-                    let fully_expanded = expand_macros(&expanded, macros)?;
+                    let fully_expanded = expand_nested(&expanded, macros, expansion, depth + 1)?;
                     result.push_str(&fully_expanded);
+                    if body.is_none() {
+                        result.push_str(&space_before_body);
+                    }
                 } else {
-                    return Err(format!("Undefined macro: '{}'", name));
+                    return Err(ErsaError::UndefinedMacro(name));
                 }
             } else {
+                expansion.plain_identifier(&name, macros);
                 result.push_str(&name);
+                result.push_str(&space_before_args);
                 if let Some(ref arg_list) = args {
                     result.push('(');
 
-                    let expanded_args = expand_macros(arg_list, macros)?;
+                    let expanded_args = expand_nested(arg_list, macros, expansion, depth)?;
                     result.push_str(&expanded_args);
                     result.push(')');
+                    result.push_str(&space_after_args);
                 }
             }
         } else {
@@ -284,10 +702,47 @@ pub fn expand_macros(
     Ok(result)
 }
 
-pub fn extract_arguments<I>(
-    chars: &mut std::iter::Peekable<I>,
-    pos: &mut usize,
-) -> Result<String, String>
+/// Consume whitespace and return it
+fn take_whitespace<I>(chars: &mut std::iter::Peekable<I>, pos: &mut usize) -> String
+where
+    I: Iterator<Item = char>,
+{
+    let mut whitespace = String::new();
+    while let Some(&ws) = chars.peek() {
+        if ws.is_whitespace() {
+            whitespace.push(ws);
+            chars.next();
+            *pos += ws.len_utf8();
+        } else {
+            break;
+        }
+    }
+    whitespace
+}
+
+/// Copy the rest of a string literal whose opening quote was already consumed
+fn copy_string_literal<I>(chars: &mut std::iter::Peekable<I>, pos: &mut usize, out: &mut String)
+where
+    I: Iterator<Item = char>,
+{
+    while let Some(ch) = chars.next() {
+        *pos += ch.len_utf8();
+        out.push(ch);
+
+        match ch {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    *pos += escaped.len_utf8();
+                    out.push(escaped);
+                }
+            }
+            '"' | '\n' => break,
+            _ => {}
+        }
+    }
+}
+
+pub fn extract_arguments<I>(chars: &mut std::iter::Peekable<I>, pos: &mut usize) -> Result<String>
 where
     I: Iterator<Item = char>,
 {
@@ -298,6 +753,10 @@ where
         *pos += ch.len_utf8();
 
         match ch {
+            '"' => {
+                args.push(ch);
+                copy_string_literal(chars, pos, &mut args);
+            }
             '(' => {
                 depth += 1;
                 args.push(ch);
@@ -315,53 +774,190 @@ where
         }
     }
 
-    Err("Unmatched parentheses in macro arguments".to_string())
+    Err(ErsaError::MacroSyntax(
+        "Unmatched parentheses in macro arguments".to_string(),
+    ))
 }
 
+/// Replace a macro's parameters and `%0` in its body.
+///
+/// Only whole identifiers outside string literals are replaced. By default
+/// every parameter is replaced in one simultaneous pass, so an argument that
+/// spells another parameter's name is left alone: `f(b, 1)!` of
+/// `define! f(a, b) { a + b }` gives `b + 1`. With `sequential`, parameters
+/// are replaced one at a time in declaration order, so later parameters are
+/// also replaced inside the values substituted for earlier ones, giving `1 + 1`.
 pub fn substitute_macro(
     macro_def: &MacroDefinition,
     args: Option<&str>,
     body: &str,
-) -> Result<String, String> {
+    sequential: bool,
+) -> Result<String> {
     let mut result = macro_def.body.clone();
 
     if !macro_def.params.is_empty() {
         let arg_values = if let Some(args_str) = args {
-            parse_argument_values(args_str)?
+            let mut values = parse_argument_values(args_str)?;
+            // `f()!` passes one empty argument to a one-parameter macro
+            if values.is_empty() && macro_def.params.len() == 1 {
+                values.push(String::new());
+            }
+            values
         } else {
-            return Err(format!(
-                "Macro '{}' expects {} arguments, but none were provided",
-                macro_def.name,
-                macro_def.params.len()
-            ));
+            return Err(ErsaError::MacroArity {
+                name: macro_def.name.clone(),
+                expected: macro_def.params.len(),
+                found: None,
+            });
         };
 
         if arg_values.len() != macro_def.params.len() {
-            return Err(format!(
-                "Macro '{}' expects {} arguments, but {} were provided",
-                macro_def.name,
-                macro_def.params.len(),
-                arg_values.len()
-            ));
+            return Err(ErsaError::MacroArity {
+                name: macro_def.name.clone(),
+                expected: macro_def.params.len(),
+                found: Some(arg_values.len()),
+            });
         }
 
-        for (param, value) in macro_def.params.iter().zip(arg_values.iter()) {
-            result = result.replace(param, value.trim());
+        let bindings: Vec<(&str, &str)> = macro_def
+            .params
+            .iter()
+            .map(String::as_str)
+            .zip(arg_values.iter().map(|v| v.trim()))
+            .collect();
+
+        if sequential {
+            for &binding in &bindings {
+                result = replace_identifiers(&result, &[binding]);
+            }
+        } else {
+            result = replace_identifiers(&result, &bindings);
         }
     }
 
     result = result.replace("%0", body.trim());
 
+    if result.contains("ifempty!(") {
+        result = expand_ifempty(&result)?;
+    }
+
+    Ok(result)
+}
+
+/// Replace each `ifempty!(value, then, else)` in a substituted macro body
+/// with `then` if `value` is empty or only whitespace, and `else` otherwise.
+/// A missing `else` is empty.
+fn expand_ifempty(code: &str) -> Result<String> {
+    let mut result = String::with_capacity(code.len());
+    let mut chars = code.chars().peekable();
+    let mut pos = 0;
+
+    while let Some(ch) = chars.next() {
+        pos += ch.len_utf8();
+
+        if ch == '"' {
+            result.push(ch);
+            copy_string_literal(&mut chars, &mut pos, &mut result);
+        } else if ch.is_alphanumeric() || ch == '_' {
+            let start = pos - ch.len_utf8();
+            let mut word = ch.to_string();
+            while let Some(&c) = chars.peek() {
+                if c.is_alphanumeric() || c == '_' {
+                    word.push(c);
+                    chars.next();
+                    pos += c.len_utf8();
+                } else {
+                    break;
+                }
+            }
+
+            if word != "ifempty" || !code[pos..].starts_with("!(") {
+                result.push_str(&code[start..pos]);
+                continue;
+            }
+            chars.next();
+            chars.next();
+            pos += 2;
+
+            let args = extract_arguments(&mut chars, &mut pos)?;
+            let mut values = parse_argument_values(&args)?;
+            if !(2..=3).contains(&values.len()) {
+                return Err(ErsaError::MacroSyntax(format!(
+                    "ifempty! expects (value, then, else), got '{}'",
+                    args.trim()
+                )));
+            }
+            values.resize(3, String::new());
+
+            let branch = if values[0].trim().is_empty() {
+                &values[1]
+            } else {
+                &values[2]
+            };
+            result.push_str(&expand_ifempty(branch)?);
+        } else {
+            result.push(ch);
+        }
+    }
+
     Ok(result)
 }
 
-pub fn parse_argument_values(args: &str) -> Result<Vec<String>, String> {
+/// Replace each whole identifier in `code` that is bound in `bindings` with
+/// its value, in a single pass, leaving string literals untouched
+fn replace_identifiers(code: &str, bindings: &[(&str, &str)]) -> String {
+    let mut result = String::with_capacity(code.len());
+    let mut chars = code.chars().peekable();
+    let mut pos = 0;
+
+    while let Some(ch) = chars.next() {
+        pos += ch.len_utf8();
+
+        if ch == '"' {
+            result.push(ch);
+            copy_string_literal(&mut chars, &mut pos, &mut result);
+        } else if ch.is_alphanumeric() || ch == '_' {
+            let mut word = ch.to_string();
+            while let Some(&c) = chars.peek() {
+                if c.is_alphanumeric() || c == '_' {
+                    word.push(c);
+                    chars.next();
+                    pos += c.len_utf8();
+                } else {
+                    break;
+                }
+            }
+
+            match bindings.iter().find(|(param, _)| *param == word) {
+                Some((_, value)) => result.push_str(value),
+                None => result.push_str(&word),
+            }
+        } else {
+            result.push(ch);
+        }
+    }
+
+    result
+}
+
+/// Split a macro call's argument list on top-level commas.
+///
+/// Commas and brackets inside nested brackets or string literals don't split.
+/// Only the whitespace around each argument is trimmed, and a trailing comma
+/// is ignored.
+pub fn parse_argument_values(args: &str) -> Result<Vec<String>> {
     let mut values = Vec::new();
     let mut current = String::new();
     let mut depth = 0;
+    let mut chars = args.chars().peekable();
+    let mut pos = 0;
 
-    for ch in args.chars() {
+    while let Some(ch) = chars.next() {
         match ch {
+            '"' => {
+                current.push(ch);
+                copy_string_literal(&mut chars, &mut pos, &mut current);
+            }
             '(' | '{' | '[' => {
                 depth += 1;
                 current.push(ch);
@@ -386,3 +982,360 @@ pub fn parse_argument_values(args: &str) -> Result<Vec<String>, String> {
 
     Ok(values)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::diagnostics::Severity;
+    use super::*;
+
+    #[test]
+    fn test_cli_defines() {
+        let options = PreprocessOptions {
+            defines: vec![
+                parse_define("MAX=100").unwrap(),
+                parse_define("DEBUG").unwrap(),
+            ],
+            ..Default::default()
+        };
+        let result = process_macros("x = MAX!; y = [DEBUG!];", &options, &mut Vec::new()).unwrap();
+        assert!(result.contains("100;"));
+        assert!(result.contains("[]"));
+
+        assert!(parse_define("=5").is_err());
+        assert!(parse_define("A B=1").is_err());
+    }
+
+    #[test]
+    fn test_cli_define_conflicts_with_source() {
+        let options = PreprocessOptions {
+            defines: vec![parse_define("MAX=100").unwrap()],
+            ..Default::default()
+        };
+        let err = process_macros("define! MAX { 5 }", &options, &mut Vec::new()).unwrap_err();
+        assert!(matches!(err, ErsaError::DuplicateMacro(ref name) if name == "MAX"));
+    }
+
+    #[test]
+    fn test_string_argument_keeps_spaces() {
+        let result = process_macros(
+            "define! show(s) { print(s); }\nshow(\" x \")!;",
+            &Default::default(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert!(result.contains("print(\" x \");"));
+
+        let values = parse_argument_values("\"a, b\",  (1, 2) ,c").unwrap();
+        assert_eq!(values, vec!["\"a, b\"", "(1, 2)", "c"]);
+    }
+
+    #[test]
+    fn test_whitespace_outside_calls_is_kept() {
+        let result = process_macros(
+            "main {\n    s = \"hi there!\";\n    x = f (1);\n}",
+            &Default::default(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(result, "main {\n    s = \"hi there!\";\n    x = f (1);\n}");
+    }
+
+    #[test]
+    fn test_keep_macro_comments() {
+        let code = "int a;\ndefine! add(a, b) { a + b }\nmain {\n    x = add(1, 2)!;\n}";
+        let options = PreprocessOptions {
+            keep_macro_comments: true,
+            ..Default::default()
+        };
+
+        let result = process_macros(code, &options, &mut Vec::new()).unwrap();
+        assert_eq!(
+            result,
+            "int a;\n// macro add(a, b)\nmain {\n    x = 1 + 2;\n}"
+        );
+
+        let result = process_macros(code, &Default::default(), &mut Vec::new()).unwrap();
+        assert!(!result.contains("// macro"));
+    }
+
+    #[test]
+    fn test_not_equal_is_not_a_macro_call() {
+        let result = process_macros(
+            "if (x != 5 && f(1)!= 2) { }",
+            &Default::default(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(result, "if (x != 5 && f(1)!= 2) { }");
+    }
+
+    #[test]
+    fn test_unused_macro_warning() {
+        let code = "define! USED { 1 }\ndefine! UNUSED { 2 }\nmain { x = USED!; }";
+        let mut options = PreprocessOptions {
+            defines: vec![parse_define("FROM_CLI=3").unwrap()],
+            ..Default::default()
+        };
+
+        let mut warnings = Vec::new();
+        process_macros(code, &options, &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+
+        options.warn_unused_macros = true;
+        process_macros(code, &options, &mut warnings).unwrap();
+        assert_eq!(warnings, ["Macro 'UNUSED' is defined but never used"]);
+    }
+
+    #[test]
+    fn test_strict_macros_typo() {
+        let code = "define! MAX { 10 }\ndefine! clamp(v) { v }\nmain { x = MAX; y = clamp(x); z = clamp(MAX!)!; }";
+
+        let mut warnings = Vec::new();
+        process_macros(code, &Default::default(), &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+
+        let options = PreprocessOptions {
+            strict_macros: true,
+            ..Default::default()
+        };
+        let mut warnings = Vec::new();
+        let result = process_macros(code, &options, &mut warnings).unwrap();
+        assert!(result.contains("x = MAX;"));
+        assert_eq!(
+            warnings,
+            [
+                "'MAX' is the name of a macro but is used without '!'",
+                "'clamp' is the name of a macro but is used without '!'",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_simultaneous_vs_sequential_substitution() {
+        let code = "define! f(a, b) { a + b }\nx = f(b, 1)!;";
+
+        let result = process_macros(code, &Default::default(), &mut Vec::new()).unwrap();
+        assert!(result.contains("x = b + 1;"));
+
+        let options = PreprocessOptions {
+            sequential_params: true,
+            ..Default::default()
+        };
+        let result = process_macros(code, &options, &mut Vec::new()).unwrap();
+        assert!(result.contains("x = 1 + 1;"));
+    }
+
+    #[test]
+    fn test_substitution_matches_whole_identifiers() {
+        let code = "define! scale(a) { max_a * a + \"a\" }\nx = scale(2)!;";
+        let result = process_macros(code, &Default::default(), &mut Vec::new()).unwrap();
+        assert!(result.contains("x = max_a * 2 + \"a\";"));
+    }
+
+    #[test]
+    fn test_undefined_macro_error() {
+        let err =
+            process_macros("main { missing!; }", &Default::default(), &mut Vec::new()).unwrap_err();
+        assert!(matches!(err, ErsaError::UndefinedMacro(ref name) if name == "missing"));
+    }
+
+    #[test]
+    fn test_macro_arity_error() {
+        let err = process_macros(
+            "define! add(a, b) { a + b }\nx = add(1)!;",
+            &Default::default(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            ErsaError::MacroArity {
+                expected: 2,
+                found: Some(1),
+                ..
+            }
+        ));
+
+        let err = process_macros(
+            "define! add(a, b) { a + b }\nx = add!;",
+            &Default::default(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ErsaError::MacroArity { found: None, .. }));
+    }
+
+    #[test]
+    fn test_macro_syntax_error() {
+        let err = process_macros(
+            "define! broken { never closed",
+            &Default::default(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ErsaError::MacroSyntax(_)));
+    }
+
+    #[test]
+    fn test_unterminated_body_reports_opening_brace() {
+        let code = "int x;\n\ndefine! broken(a) {\n    a + 1;\n";
+        let err = process_macros(code, &Default::default(), &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ErsaError::MacroSyntax("Unmatched '{' opened at line 3, column 19".to_string())
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_max_macro_body() {
+        let code = "define! BIG { 1 + 2 + 3 + 4 }\nx = BIG!;\n";
+        let options = PreprocessOptions {
+            max_macro_body: Some(8),
+            ..Default::default()
+        };
+        let err = process_macros(code, &options, &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("line 1, column 13"));
+        assert!(err.to_string().contains("8 bytes"));
+
+        let options = PreprocessOptions {
+            max_macro_body: Some(64),
+            ..Default::default()
+        };
+        let output = process_macros(code, &options, &mut Vec::new()).unwrap();
+        assert!(output.contains("x = 1 + 2 + 3 + 4;"));
+    }
+
+    #[test]
+    fn test_object_like_macros() {
+        let code = "define! MAX = 10;\ndefine! LIMIT = MAX * 2;\nx = LIMIT + MAX!;\nif (MAX != y) { }\nfoo(\"MAX\");\n";
+        let output = process_macros(code, &Default::default(), &mut Vec::new()).unwrap();
+        assert!(output.contains("x = 10 * 2 + 10;"));
+        assert!(output.contains("if (10 != y) { }"));
+        assert!(output.contains("foo(\"MAX\");"));
+        assert!(!output.contains("define!"));
+    }
+
+    #[test]
+    fn test_comments_are_not_expanded() {
+        let code =
+            "define! MAX = 10;\nx = MAX; // MAX, or MAX! or stop!\n/* MAX\n   MAX! */ y = MAX;\n";
+        let options = PreprocessOptions {
+            strict_macros: true,
+            ..Default::default()
+        };
+        let mut warnings = Vec::new();
+        let output = process_macros(code, &options, &mut warnings).unwrap();
+        assert!(output.contains("x = 10; // MAX, or MAX! or stop!\n/* MAX\n   MAX! */ y = 10;"));
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_object_like_self_reference() {
+        let code = "define! X = X + 1;\ndefine! A = B;\ndefine! B = A;\ny = X;\nz = A;\n";
+        let output = process_macros(code, &Default::default(), &mut Vec::new()).unwrap();
+        assert!(output.contains("y = X + 1;"));
+        assert!(output.contains("z = A;"));
+
+        let err = process_macros("define! X = 1", &Default::default(), &mut Vec::new());
+        assert!(matches!(err, Err(ErsaError::MacroSyntax(_))));
+    }
+
+    #[test]
+    fn test_defines_file() {
+        let contents = "# release build\nDEBUG\n\nMAX = 100\n// unused\nNAME=\"pad\"\n";
+        let defines = parse_defines_file(contents, Path::new("release.defs")).unwrap();
+        let options = PreprocessOptions {
+            defines,
+            ..Default::default()
+        };
+
+        let output = process_macros(
+            "x = MAX!;\ny = NAME!;\nz = DEBUG!1;",
+            &options,
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(output, "x = 100;\ny = \"pad\";\nz = 1;");
+
+        let err = parse_defines_file("DEBUG\n\nbad name\n", Path::new("release.defs")).unwrap_err();
+        assert!(err.to_string().starts_with("release.defs:3: "));
+    }
+
+    #[test]
+    fn test_diagnostic_spans() {
+        let code = "int x;\ndefine! broken(a) {\n    a + 1;\n";
+        let found = diagnostics(code, code, &Default::default());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].severity, Severity::Error);
+        assert_eq!(found[0].span, 7..code.len());
+
+        let code = "define! UNUSED { 1 }\nx = MISSING(1)!;\n";
+        let found = diagnostics(code, code, &Default::default());
+        assert_eq!(found.len(), 1);
+        assert_eq!(&code[found[0].span.clone()], "MISSING(1)!");
+
+        let code = "x = 1;\ndefine! UNUSED { 1 }\n";
+        let found = diagnostics(code, code, &Default::default());
+        assert_eq!(found[0].severity, Severity::Warning);
+        assert_eq!(&code[found[0].span.clone()], "define! UNUSED { 1 }");
+    }
+
+    #[test]
+    fn test_trailing_commas() {
+        let code = "define! f(a, b,) { a - b }\ndefine! g(\n    a,\n) { a }\nx = f(3, 1,)!;\ny = g(2 , )!;\n";
+        let output = process_macros(code, &Default::default(), &mut Vec::new()).unwrap();
+        assert!(output.contains("x = 3 - 1;"));
+        assert!(output.contains("y = 2;"));
+
+        let code = "define! f(a, b) { a - b }\nx = f(3,)!;\n";
+        let err = process_macros(code, &Default::default(), &mut Vec::new()).unwrap_err();
+        assert!(matches!(err, ErsaError::MacroArity { found: Some(1), .. }));
+    }
+
+    #[test]
+    fn test_ifempty() {
+        let code = "define! speed(s) { ifempty!(s, 10, s) }\na = speed()!;\nb = speed( )!;\nc = speed(4)!;\n";
+        let output = process_macros(code, &Default::default(), &mut Vec::new()).unwrap();
+        assert!(output.contains("a = 10;"));
+        assert!(output.contains("b = 10;"));
+        assert!(output.contains("c = 4;"));
+
+        let code = "define! log(msg) { print(ifempty!(msg, \"none\", msg)); ifempty!(msg, , sent = 1;) }\nlog()!\nlog(\"hi\")!\n";
+        let output = process_macros(code, &Default::default(), &mut Vec::new()).unwrap();
+        assert!(output.contains("print(\"none\"); \n"));
+        assert!(output.contains("print(\"hi\"); sent = 1;"));
+
+        let code = "define! f(a) { ifempty!(a) }\nf(1)!;\n";
+        let err = process_macros(code, &Default::default(), &mut Vec::new()).unwrap_err();
+        assert!(matches!(err, ErsaError::MacroSyntax(_)));
+    }
+
+    #[test]
+    fn test_trace_macros() {
+        let code = "define! inc(x) { x + 1 }\ndefine! twice(x) { inc(inc(x)!)! }\na = twice(5)!;\n";
+        let options = PreprocessOptions {
+            trace_macros: true,
+            ..Default::default()
+        };
+        let (output, lines) =
+            crate::log::capture(|| process_macros(code, &options, &mut Vec::new()));
+        assert!(output.unwrap().contains("a = 5 + 1 + 1;"));
+        assert_eq!(
+            lines,
+            [
+                "twice(5) => inc(inc(5)!)!",
+                "  inc(inc(5)!) => inc(5)! + 1",
+                "    inc(5) => 5 + 1",
+            ]
+        );
+
+        let (_, lines) =
+            crate::log::capture(|| process_macros(code, &Default::default(), &mut Vec::new()));
+        assert!(lines.is_empty());
+
+        let long = "x ".repeat(50);
+        assert_eq!(trace_text(&long).len(), TRACE_WIDTH + 3);
+        assert_eq!(trace_text("a\n    b"), "a b");
+    }
+}
@@ -1,18 +1,358 @@
+pub mod comments;
+pub mod definitions;
+pub mod diagnostics;
+pub mod env;
+pub mod format;
 pub mod imports;
 pub mod macros;
 pub mod optimize;
+pub mod pass;
+pub mod scan;
+pub mod validate;
 
-use std::collections::HashSet;
-use std::path::Path;
+use crate::error::Result;
+use std::path::{Path, PathBuf};
 
-pub fn preprocess(code: &str, base_path: &Path) -> Result<String, String> {
-    let mut processed = code.to_string();
+/// Source dialect being preprocessed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Language {
+    #[default]
+    Gpc,
+    Gpx,
+}
+
+impl Language {
+    /// Detect the dialect from a file extension, defaulting to GPC
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("gpx") => Language::Gpx,
+            _ => Language::Gpc,
+        }
+    }
+}
+
+/// A pass that can be selected with `--only` or skipped with `--no-<pass>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Pass {
+    Imports,
+    Macros,
+    Optimize,
+}
+
+/// Which passes `preprocess` runs; all of them by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Passes {
+    pub imports: bool,
+    pub macros: bool,
+    pub optimize: bool,
+}
+
+impl Default for Passes {
+    fn default() -> Self {
+        Passes {
+            imports: true,
+            macros: true,
+            optimize: true,
+        }
+    }
+}
+
+impl Passes {
+    /// Run exactly the listed passes
+    pub fn only(passes: &[Pass]) -> Self {
+        Passes {
+            imports: passes.contains(&Pass::Imports),
+            macros: passes.contains(&Pass::Macros),
+            optimize: passes.contains(&Pass::Optimize),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PreprocessOptions {
+    pub language: Language,
+    /// Passes to run
+    pub passes: Passes,
+    /// Macros defined on the command line with `-D`
+    pub defines: Vec<macros::MacroDefinition>,
+    /// Leave a `// macro NAME(params)` comment where each definition was
+    pub keep_macro_comments: bool,
+    /// Report every failed import instead of stopping at the first
+    pub keep_going: bool,
+    /// Directories searched for imports not found next to the importing file
+    pub include_dirs: Vec<PathBuf>,
+    /// Warn when a macro's name appears without `!`
+    pub strict_macros: bool,
+    /// Warn about macros that are defined but never expanded
+    pub warn_unused_macros: bool,
+    /// Substitute macro parameters one at a time, each also inside the values
+    /// of earlier ones, instead of all at once
+    pub sequential_params: bool,
+    /// Print the path of every inlined file to stderr, in processing order
+    pub print_imports: bool,
+    /// Print every macro expansion to stderr, in expansion order
+    pub trace_macros: bool,
+    /// Layout of the final output
+    pub format: format::Format,
+    /// Reject macro definitions whose body is longer than this many bytes
+    pub max_macro_body: Option<usize>,
+    /// Abort once more than this many files have been inlined
+    pub max_imports: Option<usize>,
+    /// Inline a file again on every import instead of skipping files that
+    /// were already inlined; `@once` imports are still skipped
+    pub reinline_imports: bool,
+}
 
-    processed = imports::process_imports(&processed, base_path, &mut HashSet::new())?;
+/// `code` without a leading UTF-8 byte order mark, which some Windows
+/// editors write and which the scanners would treat as source text
+pub fn strip_bom(code: &str) -> &str {
+    code.strip_prefix('\u{feff}').unwrap_or(code)
+}
+
+/// Run the selected passes over `code`, appending non-fatal problems to `warnings`
+pub fn preprocess(
+    code: &str,
+    base_path: &Path,
+    options: &PreprocessOptions,
+    warnings: &mut Vec<String>,
+) -> Result<String> {
+    preprocess_with(
+        code,
+        base_path,
+        options,
+        &pass::default_passes(options),
+        warnings,
+    )
+}
+
+/// Run `passes` over `code` in order, for pipelines with custom passes
+pub fn preprocess_with(
+    code: &str,
+    base_path: &Path,
+    options: &PreprocessOptions,
+    passes: &[Box<dyn pass::PreprocessPass>],
+    warnings: &mut Vec<String>,
+) -> Result<String> {
+    // The passes only know about '\n', so work on LF and restore CRLF at the end
+    let crlf = code.contains("\r\n");
+    let mut processed = code.replace("\r\n", "\n");
 
-    processed = macros::process_macros(&processed)?;
+    let mut ctx = pass::PassContext {
+        base_path,
+        options,
+        warnings,
+    };
+    for pass in passes {
+        processed = pass.run(&processed, &mut ctx)?;
+    }
 
-    processed = optimize::optimize(&processed)?;
+    if crlf {
+        processed = processed.replace('\n', "\r\n");
+    }
 
     Ok(processed)
 }
+
+/// Check the imports and macros of `code` without failing on the first
+/// problem, for editors and other tools that want located diagnostics.
+///
+/// Spans are byte offsets into `code`.
+pub fn diagnose(
+    code: &str,
+    base_path: &Path,
+    options: &PreprocessOptions,
+) -> Vec<diagnostics::Diagnostic> {
+    let mut found = imports::diagnostics(code, base_path, options);
+
+    // Macros may be defined in imported files, so collect them from the
+    // inlined source; imports that failed are already reported above
+    let inlined = imports::inline_available(code, base_path, options);
+    let inlined = comments::strip_build_comments(&inlined.replace("\r\n", "\n"));
+
+    found.extend(macros::diagnostics(code, &inlined, options));
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_language_from_path() {
+        assert_eq!(Language::from_path(Path::new("main.gpc")), Language::Gpc);
+        assert_eq!(Language::from_path(Path::new("main.GPX")), Language::Gpx);
+        assert_eq!(Language::from_path(Path::new("main")), Language::Gpc);
+    }
+
+    #[test]
+    fn test_gpx_is_not_constant_folded() {
+        let code = "x = 1 + 2;";
+
+        let gpc = preprocess(
+            code,
+            Path::new("."),
+            &PreprocessOptions::default(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert!(gpc.contains('3'));
+
+        let options = PreprocessOptions {
+            language: Language::Gpx,
+            ..Default::default()
+        };
+        let gpx = preprocess(code, Path::new("."), &options, &mut Vec::new()).unwrap();
+        assert!(gpx.contains("1 + 2"));
+    }
+
+    #[test]
+    fn test_division_by_zero_warns() {
+        let mut warnings = Vec::new();
+        let output = preprocess(
+            "x = 4 / 0;\ny = 4 / 2;",
+            Path::new("."),
+            &PreprocessOptions::default(),
+            &mut warnings,
+        )
+        .unwrap();
+
+        assert!(output.contains("4 / 0"));
+        assert!(output.contains("y = 2;"));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("Division by zero"));
+    }
+
+    #[test]
+    fn test_crlf_input() {
+        let code = "define! MAX { 10 }\r\nmain {\r\n    x = MAX! + 1;\r\n}\r\n";
+
+        let output = preprocess(
+            code,
+            Path::new("."),
+            &PreprocessOptions::default(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert!(output.contains("x = 11;\r\n"));
+        assert!(!output.contains("\r\r"));
+        assert!(!output.replace("\r\n", "").contains('\r'));
+    }
+
+    #[test]
+    fn test_crlf_import_in_lf_file() {
+        let dir = std::env::temp_dir().join(format!("ersa_crlf_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.gpc"), "int a = 2 * 3;\r\nint b;\r\n").unwrap();
+
+        let output = preprocess(
+            "import lib;\nmain { }\n",
+            &dir,
+            &PreprocessOptions::default(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert!(!output.contains('\r'));
+        assert!(output.contains("int a = 6;\nint b;"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn run_passes(code: &str, dir: &Path, passes: Passes) -> String {
+        let options = PreprocessOptions {
+            passes,
+            ..Default::default()
+        };
+        preprocess(code, dir, &options, &mut Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_single_pass_selection() {
+        let dir = std::env::temp_dir().join(format!("ersa_passes_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.gpc"), "int y = 2 * 3;\n").unwrap();
+        let code = "import lib;\n//! build note\ndefine! TWO { 2 }\nx = TWO! + 1;\n";
+
+        let imports = run_passes(code, &dir, Passes::only(&[Pass::Imports]));
+        assert!(imports.contains("int y = 2 * 3;"));
+        assert!(imports.contains("//! build note"));
+        assert!(imports.contains("define! TWO"));
+
+        let macros = run_passes(code, &dir, Passes::only(&[Pass::Macros]));
+        assert!(macros.contains("import lib;"));
+        assert!(!macros.contains("//! build note"));
+        assert!(macros.contains("x = 2 + 1;"));
+
+        let optimize = run_passes("x = 2 + 1;\n", &dir, Passes::only(&[Pass::Optimize]));
+        assert_eq!(optimize, "x = 3;\n");
+
+        let no_optimize = Passes {
+            optimize: false,
+            ..Default::default()
+        };
+        let output = run_passes(code, &dir, no_optimize);
+        assert!(output.contains("int y = 2 * 3;"));
+        assert!(output.contains("x = 2 + 1;"));
+
+        let none = run_passes(code, &dir, Passes::only(&[]));
+        assert_eq!(none, code);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pretty_format_reindents_imports() {
+        let dir = std::env::temp_dir().join(format!("ersa_format_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.gpc"), "function a() {\n\treturn 1;\n}\n").unwrap();
+        std::fs::write(dir.join("b.gpc"), "function b() {\n  return 2;\n  }\n").unwrap();
+
+        let options = PreprocessOptions {
+            format: format::Format::Pretty,
+            ..Default::default()
+        };
+        let code = "import a;\nimport b;\nmain {\n        x = a();\n}\n";
+        let output = preprocess(code, &dir, &options, &mut Vec::new()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            output,
+            "function a() {\n    return 1;\n}\n\nfunction b() {\n    return 2;\n}\n\nmain {\n    x = a();\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_diagnose_sees_imported_macros() {
+        let dir = std::env::temp_dir().join(format!("ersa_diagnose_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.gpc"), "define! SPEED { 4 }\n").unwrap();
+        let code = "import lib;\nimport missing;\nx = SPEED!;\n";
+
+        let found = diagnose(code, &dir, &PreprocessOptions::default());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].severity, diagnostics::Severity::Error);
+        assert_eq!(&code[found[0].span.clone()], "import missing;");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    struct Numbers;
+
+    impl pass::PreprocessPass for Numbers {
+        fn run(&self, code: &str, _ctx: &mut pass::PassContext) -> Result<String> {
+            Ok(code.replace("TWO", "2"))
+        }
+    }
+
+    #[test]
+    fn test_custom_pass_runs_in_order() {
+        let options = PreprocessOptions::default();
+        let mut passes = pass::default_passes(&options);
+        // Between macros and the constant folder
+        passes.insert(passes.len() - 1, Box::new(Numbers));
+
+        let code = "define! ADD(a) { a + 1 }\nx = ADD(TWO)!;\n";
+        let output =
+            preprocess_with(code, Path::new("."), &options, &passes, &mut Vec::new()).unwrap();
+        assert_eq!(output.trim_start(), "x = 3;\n");
+    }
+}
@@ -1,22 +1,45 @@
-pub fn optimize(code: &str) -> Result<String, String> {
-    constant_fold(code)
+use crate::error::{ErsaError, Result};
+
+/// Optimize `code`, appending anything worth telling the user to `warnings`
+pub fn optimize(code: &str, warnings: &mut Vec<String>) -> Result<String> {
+    constant_fold(code, warnings)
 }
 
-pub fn constant_fold(code: &str) -> Result<String, String> {
+pub fn constant_fold(code: &str, warnings: &mut Vec<String>) -> Result<String> {
     let mut result = String::new();
     let mut i = 0;
     let chars: Vec<char> = code.chars().collect();
 
     while i < chars.len() {
-        if let Some((expr_start, expr_end)) = find_foldable_expression(&chars, i) {
+        // Nothing inside a string literal is an expression
+        if chars[i] == '"' {
+            let end = string_literal_end(&chars, i);
+            result.extend(&chars[i..end]);
+            i = end;
+            continue;
+        }
+
+        let foldable = find_foldable_expression(&chars, i).filter(|&(expr_start, expr_end)| {
+            let expr: String = chars[expr_start..expr_end].iter().collect();
+            binds_tighter_than_context(&chars, expr_start, &expr)
+        });
+
+        if let Some((expr_start, expr_end)) = foldable {
             result.push_str(&chars[i..expr_start].iter().collect::<String>());
 
             let expr: String = chars[expr_start..expr_end].iter().collect();
 
-            if let Ok(value) = evaluate_expression(&expr) {
-                result.push_str(&value.to_string());
-            } else {
-                result.push_str(&expr);
+            match evaluate_expression(&expr) {
+                Ok(value) => result.push_str(&value.to_string()),
+                Err(ErsaError::Parse(message)) if message.ends_with("by zero") => {
+                    warnings.push(format!(
+                        "{} in constant expression '{}', left unfolded",
+                        message,
+                        expr.trim()
+                    ));
+                    result.push_str(&expr);
+                }
+                Err(_) => result.push_str(&expr),
             }
 
             i = expr_end;
@@ -29,6 +52,19 @@ pub fn constant_fold(code: &str) -> Result<String, String> {
     Ok(result)
 }
 
+/// Index just past the string literal opening at `start`
+fn string_literal_end(chars: &[char], start: usize) -> usize {
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            '"' | '\n' => return i + 1,
+            _ => i += 1,
+        }
+    }
+    chars.len()
+}
+
 fn find_foldable_expression(chars: &[char], start: usize) -> Option<(usize, usize)> {
     if start >= chars.len() {
         return None;
@@ -47,6 +83,12 @@ fn find_foldable_expression(chars: &[char], start: usize) -> Option<(usize, usiz
         return None;
     }
 
+    // Digits inside an identifier such as `var_1` don't start an expression
+    if i > 0 && chars[i].is_ascii_digit() && (chars[i - 1].is_alphanumeric() || chars[i - 1] == '_')
+    {
+        return None;
+    }
+
     let expr_start = i;
     let mut depth = 0;
     let mut has_operator = false;
@@ -60,38 +102,43 @@ fn find_foldable_expression(chars: &[char], start: usize) -> Option<(usize, usiz
                 last_was_number = true;
                 i += 1;
             }
+            '_' if last_was_number => {
+                i += 1;
+            }
             '+' | '*' | '/' | '%' | '&' | '|' | '^' => {
                 has_operator = true;
                 last_was_number = false;
                 i += 1;
             }
             '-' => {
-                has_operator = true;
+                // Only a minus after an operand is binary; `-5` alone isn't foldable
+                has_operator |= last_was_number;
                 last_was_number = false;
                 i += 1;
             }
             '<' | '>' => {
-                if i + 1 < chars.len() && chars[i + 1] == ch {
-                    has_operator = true;
-                    i += 2;
-                    last_was_number = false;
+                has_operator = true;
+                i += if chars.get(i + 1).is_some_and(|&c| c == ch || c == '=') {
+                    2
                 } else {
-                    break;
-                }
+                    1
+                };
+                last_was_number = false;
+            }
+            '=' | '!' if chars.get(i + 1) == Some(&'=') => {
+                has_operator = true;
+                i += 2;
+                last_was_number = false;
             }
             '(' => {
                 depth += 1;
                 last_was_number = false;
                 i += 1;
             }
-            ')' => {
-                if depth > 0 {
-                    depth -= 1;
-                    last_was_number = true;
-                    i += 1;
-                } else {
-                    break;
-                }
+            ')' if depth > 0 => {
+                depth -= 1;
+                last_was_number = true;
+                i += 1;
             }
             ' ' | '\t' => {
                 i += 1;
@@ -112,10 +159,87 @@ fn find_foldable_expression(chars: &[char], start: usize) -> Option<(usize, usiz
     }
 }
 
-fn evaluate_expression(expr: &str) -> Result<i64, String> {
+/// Whether `expr`, found at `start`, can be folded without changing how the
+/// surrounding code groups it.
+///
+/// In `a * 1 + 2` the span `1 + 2` is constant, but `*` binds tighter than
+/// `+`, so folding it would turn `(a * 1) + 2` into `a * 3`.
+fn binds_tighter_than_context(chars: &[char], start: usize, expr: &str) -> bool {
+    let Ok(tokens) = tokenize(expr) else {
+        return true;
+    };
+    let Some(lowest) = lowest_precedence(&tokens) else {
+        return true;
+    };
+
+    let mut i = start;
+    while i > 0 && chars[i - 1].is_whitespace() {
+        i -= 1;
+    }
+    let before = |n: usize| {
+        if i >= n {
+            chars.get(i - n).copied()
+        } else {
+            None
+        }
+    };
+
+    let outer = match before(1) {
+        None => 0,
+        // A '-' after an operand is binary, so the expression doesn't start here
+        Some(c) if c.is_alphanumeric() || c == '_' || c == ')' || c == ']' => {
+            if chars[start] == '-' {
+                return false;
+            }
+            0
+        }
+        Some('=') => match before(2) {
+            Some('<' | '>') if before(3) == before(2) => 0,
+            Some('=' | '!') => 7,
+            Some('<' | '>') => 8,
+            _ => 0,
+        },
+        Some('+' | '-') => 10,
+        Some('*' | '/' | '%') => 11,
+        Some('&') if before(2) == Some('&') => 3,
+        Some('&') => 6,
+        Some('|') if before(2) == Some('|') => 1,
+        Some('|') => 4,
+        Some('^') => 5,
+        Some('<' | '>') if before(2) == before(1) => 9,
+        Some('<' | '>') => 8,
+        // Unary operators bind tighter than anything
+        Some('!' | '~') => u8::MAX,
+        Some(_) => 0,
+    };
+
+    lowest > outer
+}
+
+/// Precedence of the loosest-binding operator outside parentheses
+fn lowest_precedence(tokens: &[ExprToken]) -> Option<u8> {
+    let mut depth = 0;
+    let mut lowest = None;
+
+    for token in tokens {
+        match token {
+            ExprToken::LParen => depth += 1,
+            ExprToken::RParen => depth -= 1,
+            _ if depth == 0 && precedence(token) > 0 => {
+                let prec = precedence(token);
+                lowest = Some(lowest.map_or(prec, |l: u8| l.min(prec)));
+            }
+            _ => {}
+        }
+    }
+
+    lowest
+}
+
+fn evaluate_expression(expr: &str) -> Result<i64> {
     let tokens = tokenize(expr)?;
     if !is_constant_expression(&tokens) {
-        return Err("Not a constant expression".to_string());
+        return Err(ErsaError::Parse("Not a constant expression".to_string()));
     }
     parse_expression(&tokens, 0).map(|(val, _)| val)
 }
@@ -137,11 +261,17 @@ enum ExprToken {
     And,
     Or,
     Xor,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
     LParen,
     RParen,
 }
 
-fn tokenize(expr: &str) -> Result<Vec<ExprToken>, String> {
+fn tokenize(expr: &str) -> Result<Vec<ExprToken>> {
     let mut tokens = Vec::new();
     let mut chars = expr.trim().chars().peekable();
 
@@ -151,18 +281,7 @@ fn tokenize(expr: &str) -> Result<Vec<ExprToken>, String> {
                 chars.next();
             }
             '0'..='9' => {
-                let mut num = String::new();
-                while let Some(&digit) = chars.peek() {
-                    if digit.is_ascii_digit() {
-                        num.push(digit);
-                        chars.next();
-                    } else {
-                        break;
-                    }
-                }
-                tokens.push(ExprToken::Number(
-                    num.parse().map_err(|_| "Invalid number")?,
-                ));
+                tokens.push(ExprToken::Number(read_number(&mut chars, false)?));
             }
             '+' => {
                 chars.next();
@@ -182,18 +301,7 @@ fn tokenize(expr: &str) -> Result<Vec<ExprToken>, String> {
                             | Some(ExprToken::Modulo)
                     )
                 {
-                    let mut num = String::from("-");
-                    while let Some(&digit) = chars.peek() {
-                        if digit.is_ascii_digit() {
-                            num.push(digit);
-                            chars.next();
-                        } else {
-                            break;
-                        }
-                    }
-                    tokens.push(ExprToken::Number(
-                        num.parse().map_err(|_| "Invalid number")?,
-                    ));
+                    tokens.push(ExprToken::Number(read_number(&mut chars, true)?));
                 } else {
                     tokens.push(ExprToken::Minus);
                 }
@@ -237,10 +345,11 @@ fn tokenize(expr: &str) -> Result<Vec<ExprToken>, String> {
                 if chars.peek() == Some(&'<') {
                     chars.next();
                     tokens.push(ExprToken::LeftShift);
+                } else if chars.peek() == Some(&'=') {
+                    chars.next();
+                    tokens.push(ExprToken::LessEqual);
                 } else {
-                    return Err(
-                        "Comparison operators not supported in constant expressions".to_string()
-                    );
+                    tokens.push(ExprToken::Less);
                 }
             }
             '>' => {
@@ -248,12 +357,28 @@ fn tokenize(expr: &str) -> Result<Vec<ExprToken>, String> {
                 if chars.peek() == Some(&'>') {
                     chars.next();
                     tokens.push(ExprToken::RightShift);
+                } else if chars.peek() == Some(&'=') {
+                    chars.next();
+                    tokens.push(ExprToken::GreaterEqual);
                 } else {
-                    return Err(
-                        "Comparison operators not supported in constant expressions".to_string()
-                    );
+                    tokens.push(ExprToken::Greater);
                 }
             }
+            '=' | '!' => {
+                chars.next();
+                if chars.peek() != Some(&'=') {
+                    return Err(ErsaError::Parse(format!(
+                        "Unexpected character in expression: '{}'",
+                        ch
+                    )));
+                }
+                chars.next();
+                tokens.push(if ch == '=' {
+                    ExprToken::Equal
+                } else {
+                    ExprToken::NotEqual
+                });
+            }
             '(' => {
                 chars.next();
                 tokens.push(ExprToken::LParen);
@@ -263,7 +388,10 @@ fn tokenize(expr: &str) -> Result<Vec<ExprToken>, String> {
                 tokens.push(ExprToken::RParen);
             }
             _ => {
-                return Err(format!("Unexpected character in expression: '{}'", ch));
+                return Err(ErsaError::Parse(format!(
+                    "Unexpected character in expression: '{}'",
+                    ch
+                )));
             }
         }
     }
@@ -271,6 +399,38 @@ fn tokenize(expr: &str) -> Result<Vec<ExprToken>, String> {
     Ok(tokens)
 }
 
+/// Read a decimal literal, allowing single `_` separators between digits
+fn read_number<I>(chars: &mut std::iter::Peekable<I>, negative: bool) -> Result<i64>
+where
+    I: Iterator<Item = char>,
+{
+    let mut literal = String::new();
+    while let Some(&c) = chars.peek() {
+        if c.is_ascii_digit() || c == '_' {
+            literal.push(c);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+
+    if literal.starts_with('_') || literal.ends_with('_') || literal.contains("__") {
+        return Err(ErsaError::Parse(format!(
+            "Malformed digit separators in '{}'",
+            literal
+        )));
+    }
+
+    let digits = literal.replace('_', "");
+    let num = if negative {
+        format!("-{}", digits)
+    } else {
+        digits
+    };
+    num.parse()
+        .map_err(|_| ErsaError::Parse("Invalid number".to_string()))
+}
+
 fn is_constant_expression(tokens: &[ExprToken]) -> bool {
     !tokens.is_empty()
         && tokens.iter().all(|t| {
@@ -290,6 +450,12 @@ fn is_constant_expression(tokens: &[ExprToken]) -> bool {
                     | ExprToken::And
                     | ExprToken::Or
                     | ExprToken::Xor
+                    | ExprToken::Equal
+                    | ExprToken::NotEqual
+                    | ExprToken::Less
+                    | ExprToken::LessEqual
+                    | ExprToken::Greater
+                    | ExprToken::GreaterEqual
                     | ExprToken::LParen
                     | ExprToken::RParen
             )
@@ -304,6 +470,8 @@ fn precedence(token: &ExprToken) -> u8 {
         ExprToken::BitOr => 4,
         ExprToken::BitXor => 5,
         ExprToken::BitAnd => 6,
+        ExprToken::Equal | ExprToken::NotEqual => 7,
+        ExprToken::Less | ExprToken::LessEqual | ExprToken::Greater | ExprToken::GreaterEqual => 8,
         ExprToken::LeftShift | ExprToken::RightShift => 9,
         ExprToken::Plus | ExprToken::Minus => 10,
         ExprToken::Multiply | ExprToken::Divide | ExprToken::Modulo => 11,
@@ -311,7 +479,7 @@ fn precedence(token: &ExprToken) -> u8 {
     }
 }
 
-fn parse_expression(tokens: &[ExprToken], pos: usize) -> Result<(i64, usize), String> {
+fn parse_expression(tokens: &[ExprToken], pos: usize) -> Result<(i64, usize)> {
     parse_binary_expression(tokens, pos, 0)
 }
 
@@ -319,7 +487,7 @@ fn parse_binary_expression(
     tokens: &[ExprToken],
     mut pos: usize,
     min_prec: u8,
-) -> Result<(i64, usize), String> {
+) -> Result<(i64, usize)> {
     let (mut left, new_pos) = parse_primary(tokens, pos)?;
     pos = new_pos;
 
@@ -346,9 +514,9 @@ fn parse_binary_expression(
     Ok((left, pos))
 }
 
-fn parse_primary(tokens: &[ExprToken], pos: usize) -> Result<(i64, usize), String> {
+fn parse_primary(tokens: &[ExprToken], pos: usize) -> Result<(i64, usize)> {
     if pos >= tokens.len() {
-        return Err("Unexpected end of expression".to_string());
+        return Err(ErsaError::Parse("Unexpected end of expression".to_string()));
     }
 
     match &tokens[pos] {
@@ -356,7 +524,7 @@ fn parse_primary(tokens: &[ExprToken], pos: usize) -> Result<(i64, usize), Strin
         ExprToken::LParen => {
             let (value, new_pos) = parse_expression(tokens, pos + 1)?;
             if new_pos >= tokens.len() || !matches!(tokens[new_pos], ExprToken::RParen) {
-                return Err("Missing closing parenthesis".to_string());
+                return Err(ErsaError::Parse("Missing closing parenthesis".to_string()));
             }
             Ok((value, new_pos + 1))
         }
@@ -364,25 +532,28 @@ fn parse_primary(tokens: &[ExprToken], pos: usize) -> Result<(i64, usize), Strin
             let (value, new_pos) = parse_primary(tokens, pos + 1)?;
             Ok((-value, new_pos))
         }
-        _ => Err(format!("Unexpected token in expression: {:?}", tokens[pos])),
+        _ => Err(ErsaError::Parse(format!(
+            "Unexpected token in expression: {:?}",
+            tokens[pos]
+        ))),
     }
 }
 
-fn apply_operator(left: i64, op: &ExprToken, right: i64) -> Result<i64, String> {
+fn apply_operator(left: i64, op: &ExprToken, right: i64) -> Result<i64> {
     match op {
         ExprToken::Plus => Ok(left.wrapping_add(right)),
         ExprToken::Minus => Ok(left.wrapping_sub(right)),
         ExprToken::Multiply => Ok(left.wrapping_mul(right)),
         ExprToken::Divide => {
             if right == 0 {
-                Err("Division by zero".to_string())
+                Err(ErsaError::Parse("Division by zero".to_string()))
             } else {
                 Ok(left / right)
             }
         }
         ExprToken::Modulo => {
             if right == 0 {
-                Err("Modulo by zero".to_string())
+                Err(ErsaError::Parse("Modulo by zero".to_string()))
             } else {
                 Ok(left % right)
             }
@@ -395,6 +566,94 @@ fn apply_operator(left: i64, op: &ExprToken, right: i64) -> Result<i64, String>
         ExprToken::And => Ok(if left != 0 && right != 0 { 1 } else { 0 }),
         ExprToken::Or => Ok(if left != 0 || right != 0 { 1 } else { 0 }),
         ExprToken::Xor => Ok(if (left != 0) != (right != 0) { 1 } else { 0 }),
-        _ => Err(format!("Invalid operator: {:?}", op)),
+        ExprToken::Equal => Ok((left == right) as i64),
+        ExprToken::NotEqual => Ok((left != right) as i64),
+        ExprToken::Less => Ok((left < right) as i64),
+        ExprToken::LessEqual => Ok((left <= right) as i64),
+        ExprToken::Greater => Ok((left > right) as i64),
+        ExprToken::GreaterEqual => Ok((left >= right) as i64),
+        _ => Err(ErsaError::Parse(format!("Invalid operator: {:?}", op))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fold(code: &str) -> String {
+        constant_fold(code, &mut Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_comparisons_and_logic() {
+        assert_eq!(fold("x = (1 < 2) && (3 > 4);"), "x = 0;");
+        assert_eq!(fold("x = (1 < 2) || (3 > 4);"), "x = 1;");
+        assert_eq!(fold("x = 1 < 2 && 3 >= 3;"), "x = 1;");
+        assert_eq!(fold("x = 2 == 2 || 1 != 1;"), "x = 1;");
+        assert_eq!(fold("x = 1 + 1 == 2;"), "x = 1;");
+        assert_eq!(fold("x = 1 | 2 == 2;"), "x = 1;");
+        assert_eq!(fold("x = 5 <= 4;"), "x = 0;");
+    }
+
+    #[test]
+    fn test_digit_separators() {
+        assert_eq!(fold("x = 1_000 + 1;"), "x = 1001;");
+        assert_eq!(fold("x = 1_000_000 * 2;"), "x = 2000000;");
+        assert_eq!(fold("x = 3 - -1_0;"), "x = 13;");
+        assert_eq!(fold("x = 1__0 + 1;"), "x = 1__0 + 1;");
+        assert_eq!(fold("x = 5_ + 1;"), "x = 5_ + 1;");
+        assert_eq!(fold("x = var_1 + 2;"), "x = var_1 + 2;");
+    }
+
+    #[test]
+    fn test_strings_are_not_folded() {
+        assert_eq!(fold(r#"s = "1 + 2"; x = 1 + 2;"#), r#"s = "1 + 2"; x = 3;"#);
+        assert_eq!(fold(r#"s = "a\" 2 * 3";"#), r#"s = "a\" 2 * 3";"#);
+    }
+
+    #[test]
+    fn test_lone_literals_untouched() {
+        for code in [
+            "x = -5;",
+            "x = - 5;",
+            "x = 42;",
+            "f(-1, (-2));",
+            "x = -(3);",
+        ] {
+            assert_eq!(fold(code), code);
+        }
+        assert_eq!(fold("x = -5 + 1;"), "x = -4;");
+        assert_eq!(fold("x = 2 - -5;"), "x = 7;");
+    }
+
+    #[test]
+    fn test_context_precedence_respected() {
+        assert_eq!(fold("x = a * 1 + 2;"), "x = a * 1 + 2;");
+        assert_eq!(fold("x = a * 1 + 2 * 3;"), "x = a * 1 + 6;");
+        assert_eq!(fold("x = a + 1 < 2;"), "x = a + 1 < 2;");
+        assert_eq!(fold("x = a == 1 && 1;"), "x = a == 1 && 1;");
+        assert_eq!(fold("x = a && 1 < 2;"), "x = a && 1;");
+        assert_eq!(fold("x = a - 1 + 2;"), "x = a - 1 + 2;");
+        assert_eq!(fold("x = a * (1 + 2);"), "x = a * 3;");
+        assert_eq!(fold("if (a < 2) { }"), "if (a < 2) { }");
+    }
+
+    #[test]
+    fn test_left_associativity() {
+        assert_eq!(fold("x = 10 - 2 - 3;"), "x = 5;");
+        assert_eq!(fold("x = 16 / 4 / 2;"), "x = 2;");
+        assert_eq!(fold("x = 17 % 10 % 4;"), "x = 3;");
+        assert_eq!(fold("x = 1 - 2 + 3;"), "x = 2;");
+        assert_eq!(fold("x = 100 / 10 % 3;"), "x = 1;");
+        assert_eq!(fold("x = 64 >> 2 >> 1;"), "x = 8;");
+    }
+
+    #[test]
+    fn test_unary_minus_associativity() {
+        assert_eq!(fold("x = -10 - 2 - 3;"), "x = -15;");
+        assert_eq!(fold("x = 10 - -2 - 3;"), "x = 9;");
+        assert_eq!(fold("x = 2 - 3 - -4;"), "x = 3;");
+        assert_eq!(fold("x = a - 10 - 2;"), "x = a - 10 - 2;");
+        assert_eq!(fold("x = a / 8 / 2;"), "x = a / 8 / 2;");
     }
 }
@@ -0,0 +1,105 @@
+use super::{Language, PreprocessOptions, comments, env, format, imports, macros, optimize};
+use crate::error::Result;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// What a pass can see besides the code it transforms
+pub struct PassContext<'a> {
+    /// Directory imports in the entry file are resolved against
+    pub base_path: &'a Path,
+    pub options: &'a PreprocessOptions,
+    /// Non-fatal problems found so far
+    pub warnings: &'a mut Vec<String>,
+}
+
+/// One step of the preprocessing pipeline.
+///
+/// Passes run in order, each on the output of the previous one, and always
+/// see `\n` line endings.
+pub trait PreprocessPass {
+    fn run(&self, code: &str, ctx: &mut PassContext) -> Result<String>;
+}
+
+/// Inline `import` statements
+pub struct ImportsPass;
+
+impl PreprocessPass for ImportsPass {
+    fn run(&self, code: &str, ctx: &mut PassContext) -> Result<String> {
+        let inlined = imports::process_imports(
+            code,
+            ctx.base_path,
+            &mut HashSet::new(),
+            ctx.options,
+            ctx.warnings,
+        )?;
+        // Imported files may use either line ending
+        Ok(inlined.replace("\r\n", "\n"))
+    }
+}
+
+/// Remove `//!` build comments
+pub struct CommentsPass;
+
+impl PreprocessPass for CommentsPass {
+    fn run(&self, code: &str, _ctx: &mut PassContext) -> Result<String> {
+        Ok(comments::strip_build_comments(code))
+    }
+}
+
+/// Replace `env!("NAME")` with environment variables, before macros so it
+/// also works in macro bodies
+pub struct EnvPass;
+
+impl PreprocessPass for EnvPass {
+    fn run(&self, code: &str, _ctx: &mut PassContext) -> Result<String> {
+        env::expand_env(code)
+    }
+}
+
+/// Expand macros
+pub struct MacrosPass;
+
+impl PreprocessPass for MacrosPass {
+    fn run(&self, code: &str, ctx: &mut PassContext) -> Result<String> {
+        macros::process_macros(code, ctx.options, ctx.warnings)
+    }
+}
+
+/// Fold constant expressions
+pub struct OptimizePass;
+
+impl PreprocessPass for OptimizePass {
+    fn run(&self, code: &str, ctx: &mut PassContext) -> Result<String> {
+        optimize::optimize(code, ctx.warnings)
+    }
+}
+
+/// Reindent or compact the output
+pub struct FormatPass(pub format::Format);
+
+impl PreprocessPass for FormatPass {
+    fn run(&self, code: &str, _ctx: &mut PassContext) -> Result<String> {
+        Ok(format::format(code, self.0))
+    }
+}
+
+/// The built-in pipeline selected by `options.passes`
+pub fn default_passes(options: &PreprocessOptions) -> Vec<Box<dyn PreprocessPass>> {
+    let mut passes: Vec<Box<dyn PreprocessPass>> = Vec::new();
+    if options.passes.imports {
+        passes.push(Box::new(ImportsPass));
+    }
+    if options.passes.macros {
+        passes.push(Box::new(CommentsPass));
+        passes.push(Box::new(EnvPass));
+        passes.push(Box::new(MacrosPass));
+    }
+    // The constant folder only understands GPC expressions and would mangle GPX
+    if options.passes.optimize && options.language == Language::Gpc {
+        passes.push(Box::new(OptimizePass));
+    }
+    if options.format != format::Format::None {
+        passes.push(Box::new(FormatPass(options.format)));
+    }
+    passes
+}
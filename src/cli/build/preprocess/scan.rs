@@ -0,0 +1,55 @@
+/// Length of the string literal or comment at the start of `rest`, if there
+/// is one; see [`string_len`] and [`comment_len`]
+pub fn skipped_len(rest: &str) -> Option<usize> {
+    string_len(rest).or_else(|| comment_len(rest))
+}
+
+/// Length of the string literal at the start of `rest`, including its quotes,
+/// if there is one. A backslash escapes the next character. GPC strings
+/// can't span lines, so an unterminated literal ends before its newline.
+pub fn string_len(rest: &str) -> Option<usize> {
+    if !rest.starts_with('"') {
+        return None;
+    }
+
+    let bytes = rest.as_bytes();
+    let mut i = 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if bytes.get(i + 1).is_some_and(|&b| b != b'\n') => i += 1,
+            b'"' => return Some(i + 1),
+            b'\n' => return Some(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    Some(bytes.len())
+}
+
+/// Length of the `//` or `/* */` comment at the start of `rest`, if there is
+/// one. A line comment ends before its newline; an unterminated block comment
+/// runs to the end.
+pub fn comment_len(rest: &str) -> Option<usize> {
+    if rest.starts_with("//") {
+        return Some(rest.find('\n').unwrap_or(rest.len()));
+    }
+    rest.strip_prefix("/*")
+        .map(|comment| comment.find("*/").map_or(rest.len(), |end| end + 4))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skipped_len() {
+        assert_eq!(skipped_len("\"a \\\" b\" rest"), Some(8));
+        assert_eq!(skipped_len("\"open\nnext"), Some(5));
+        assert_eq!(skipped_len("\"ends in \\\nnext"), Some(10));
+        assert_eq!(skipped_len("// note\nx"), Some(7));
+        assert_eq!(skipped_len("/* a\nb */ x"), Some(9));
+        assert_eq!(skipped_len("/* open"), Some(7));
+        assert_eq!(skipped_len("x = 1;"), None);
+        assert_eq!(skipped_len("/ 2"), None);
+    }
+}
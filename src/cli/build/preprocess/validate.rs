@@ -0,0 +1,162 @@
+/// A lexical problem found in a source file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LexError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+/// Check that string literals are terminated and brackets are balanced.
+///
+/// Comments and the contents of strings are skipped, so brackets inside them
+/// don't count. GPC strings cannot span lines.
+pub fn check(code: &str) -> Result<(), LexError> {
+    let mut open: Vec<(char, usize, usize)> = Vec::new();
+    let mut chars = code.chars().peekable();
+    let mut line = 1;
+    let mut column = 0;
+
+    let error = |line, column, message: String| LexError {
+        line,
+        column,
+        message,
+    };
+
+    while let Some(ch) = chars.next() {
+        column += 1;
+
+        match ch {
+            '\n' => {
+                line += 1;
+                column = 0;
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                let (start_line, start_column) = (line, column);
+                chars.next();
+                column += 1;
+
+                let mut closed = false;
+                while let Some(c) = chars.next() {
+                    column += 1;
+                    if c == '\n' {
+                        line += 1;
+                        column = 0;
+                    } else if c == '*' && chars.peek() == Some(&'/') {
+                        chars.next();
+                        column += 1;
+                        closed = true;
+                        break;
+                    }
+                }
+                if !closed {
+                    return Err(error(
+                        start_line,
+                        start_column,
+                        "Unterminated block comment".to_string(),
+                    ));
+                }
+            }
+            '"' => {
+                let (start_line, start_column) = (line, column);
+
+                let mut closed = false;
+                while let Some(&c) = chars.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    chars.next();
+                    column += 1;
+                    if c == '\\' {
+                        if chars.peek().is_some_and(|&c| c != '\n') {
+                            chars.next();
+                            column += 1;
+                        }
+                    } else if c == '"' {
+                        closed = true;
+                        break;
+                    }
+                }
+                if !closed {
+                    return Err(error(
+                        start_line,
+                        start_column,
+                        "Unterminated string literal".to_string(),
+                    ));
+                }
+            }
+            '(' | '[' | '{' => open.push((ch, line, column)),
+            ')' | ']' | '}' => {
+                let expected = match ch {
+                    ')' => '(',
+                    ']' => '[',
+                    _ => '{',
+                };
+                match open.pop() {
+                    Some((opener, _, _)) if opener == expected => {}
+                    Some((opener, open_line, open_column)) => {
+                        return Err(error(
+                            line,
+                            column,
+                            format!(
+                                "Mismatched '{}' (the '{}' opened at line {}, column {} is still open)",
+                                ch, opener, open_line, open_column
+                            ),
+                        ));
+                    }
+                    None => {
+                        return Err(error(line, column, format!("Unmatched '{}'", ch)));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some((opener, open_line, open_column)) = open.pop() {
+        return Err(error(
+            open_line,
+            open_column,
+            format!("Unclosed '{}'", opener),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_code() {
+        assert!(
+            check("main {\n    x = (1 + 2) * a[0]; // }\n    s = \"{ \\\" (\";\n}\n/* ( */")
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_unterminated_string() {
+        let err = check("main {\n    s = \"oops;\n}").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 9));
+        assert_eq!(err.message, "Unterminated string literal");
+    }
+
+    #[test]
+    fn test_unbalanced_braces() {
+        let err = check("main {\n    if (x) {\n}").unwrap_err();
+        assert_eq!((err.line, err.column), (1, 6));
+        assert_eq!(err.message, "Unclosed '{'");
+
+        let err = check("x = (1 + 2];").unwrap_err();
+        assert_eq!((err.line, err.column), (1, 11));
+
+        let err = check("}\n").unwrap_err();
+        assert_eq!(err.message, "Unmatched '}'");
+    }
+}
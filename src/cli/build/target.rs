@@ -0,0 +1,73 @@
+use crate::config::Config;
+use crate::error::{ErsaError, Result};
+use serde::{Deserialize, Serialize};
+
+/// Limits of a device the built script is meant for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TargetProfile {
+    /// Largest build output accepted, in bytes
+    pub max_size: usize,
+}
+
+/// Profiles available without any configuration
+const BUILT_IN: &[(&str, TargetProfile)] = &[
+    ("zen", TargetProfile { max_size: 65_536 }),
+    ("titan-two", TargetProfile { max_size: 131_072 }),
+];
+
+/// Look up the profile called `name`. Profiles under `[targets.<name>]` in
+/// the config replace built-in ones of the same name.
+pub fn profile(name: &str, config: &Config) -> Result<TargetProfile> {
+    if let Some(profile) = config.targets.get(name) {
+        return Ok(*profile);
+    }
+    if let Some((_, profile)) = BUILT_IN.iter().find(|(known, _)| *known == name) {
+        return Ok(*profile);
+    }
+
+    let mut known: Vec<&str> = BUILT_IN.iter().map(|(known, _)| *known).collect();
+    known.extend(config.targets.keys().map(String::as_str));
+    known.sort();
+    known.dedup();
+    Err(ErsaError::Config(format!(
+        "Unknown target '{}'. Known targets: {}",
+        name,
+        known.join(", ")
+    )))
+}
+
+/// Fail if `code` doesn't fit the limits of target `name`
+pub fn check(name: &str, profile: &TargetProfile, code: &str) -> Result<()> {
+    if code.len() > profile.max_size {
+        return Err(ErsaError::TargetLimit {
+            target: name.to_string(),
+            size: code.len(),
+            limit: profile.max_size,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let mut config = Config::default();
+        assert_eq!(profile("zen", &config).unwrap().max_size, 65_536);
+        assert!(profile("toaster", &config).is_err());
+
+        config
+            .targets
+            .insert("zen".to_string(), TargetProfile { max_size: 10 });
+        config
+            .targets
+            .insert("tiny".to_string(), TargetProfile { max_size: 4 });
+        assert_eq!(profile("zen", &config).unwrap().max_size, 10);
+        assert_eq!(profile("tiny", &config).unwrap().max_size, 4);
+
+        let err = profile("toaster", &config).unwrap_err();
+        assert!(err.to_string().ends_with("tiny, titan-two, zen"));
+    }
+}
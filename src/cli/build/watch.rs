@@ -0,0 +1,143 @@
+use std::io::{IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// What the watch loop does on each change, so it can be driven without a
+/// real build
+pub trait Rebuild {
+    /// Build once and return the files to watch for the next change
+    fn rebuild(&mut self) -> Vec<PathBuf>;
+
+    /// Clear the terminal before a rebuild
+    fn clear(&mut self);
+}
+
+/// Rebuilds whenever one of the files read by the last build changes
+pub struct Watch<R> {
+    rebuilder: R,
+    clear: bool,
+    /// Modification time of each watched file at the last build, `None`
+    /// before the first build
+    stamps: Option<Vec<(PathBuf, Option<SystemTime>)>>,
+}
+
+impl<R: Rebuild> Watch<R> {
+    pub fn new(rebuilder: R, clear: bool) -> Self {
+        Watch {
+            rebuilder,
+            clear,
+            stamps: None,
+        }
+    }
+
+    /// Rebuild if nothing was built yet or a watched file changed, returning
+    /// whether it did
+    pub fn poll(&mut self) -> bool {
+        if let Some(stamps) = &self.stamps
+            && stamps.iter().all(|(path, time)| modified(path) == *time)
+        {
+            return false;
+        }
+
+        if self.clear {
+            self.rebuilder.clear();
+        }
+        let files = self.rebuilder.rebuild();
+        self.stamps = Some(
+            files
+                .into_iter()
+                .map(|path| {
+                    let time = modified(&path);
+                    (path, time)
+                })
+                .collect(),
+        );
+        true
+    }
+
+    /// Poll every `interval` until the process is stopped
+    pub fn run(mut self, interval: Duration) -> ! {
+        loop {
+            self.poll();
+            std::thread::sleep(interval);
+        }
+    }
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Clear the terminal and move the cursor home, unless stdout isn't a terminal
+pub fn clear_screen() {
+    let mut stdout = std::io::stdout();
+    if stdout.is_terminal() {
+        let _ = write!(stdout, "\x1b[2J\x1b[H");
+        let _ = stdout.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[derive(Default)]
+    struct Counter {
+        files: Vec<PathBuf>,
+        builds: usize,
+        clears: usize,
+    }
+
+    impl Rebuild for &mut Counter {
+        fn rebuild(&mut self) -> Vec<PathBuf> {
+            self.builds += 1;
+            self.files.clone()
+        }
+
+        fn clear(&mut self) {
+            self.clears += 1;
+        }
+    }
+
+    fn touch(path: &PathBuf, secs: u64) {
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_clear_runs_before_each_rebuild() {
+        let dir = std::env::temp_dir().join(format!("ersa_watch_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("main.gpc");
+        fs::write(&file, "main { }").unwrap();
+        touch(&file, 1_000);
+
+        let mut counter = Counter {
+            files: vec![file.clone()],
+            ..Default::default()
+        };
+        let mut watch = Watch::new(&mut counter, true);
+        assert!(watch.poll());
+        assert!(!watch.poll());
+        touch(&file, 2_000);
+        assert!(watch.poll());
+        assert!(!watch.poll());
+        drop(watch);
+        assert_eq!((counter.builds, counter.clears), (2, 2));
+
+        let mut counter = Counter {
+            files: vec![file.clone()],
+            ..Default::default()
+        };
+        let mut watch = Watch::new(&mut counter, false);
+        watch.poll();
+        touch(&file, 3_000);
+        watch.poll();
+        drop(watch);
+        assert_eq!((counter.builds, counter.clears), (2, 0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+use clap::{Args, Subcommand};
+
+use crate::config::Config;
+use crate::error::{ErsaError, Result};
+
+#[derive(Args, Debug)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Print the value of a config key, e.g. `github.token`
+    Get { key: String },
+    /// Set a config key, creating the config file if needed
+    Set { key: String, value: String },
+}
+
+pub async fn run(args: ConfigArgs) -> Result<()> {
+    // Work on the file itself so env vars don't leak into it
+    let loaded = Config::load();
+
+    match args.command {
+        ConfigCommand::Get { key } => {
+            match loaded?.get_key(&key)? {
+                Some(value) => println!("{}", value),
+                None => crate::log::info(&format!("'{}' is not set", key)),
+            }
+            Ok(())
+        }
+        ConfigCommand::Set { key, value } => {
+            let mut config = replace_invalid(loaded)?;
+            config.set_key(&key, &value)?;
+            config.save()?;
+            crate::log::success(&format!(
+                "Set '{}' in {}",
+                key,
+                crate::paths::config_path().display()
+            ));
+            Ok(())
+        }
+    }
+}
+
+/// The loaded config, or the defaults with a warning if the file doesn't
+/// parse, so `config set` can replace a broken file. Other errors, such as
+/// an unreadable file, are returned.
+fn replace_invalid(loaded: Result<Config>) -> Result<Config> {
+    match loaded {
+        Err(ErsaError::Config(message)) => {
+            crate::log::warn(&message);
+            crate::log::warn("Starting from the defaults; other settings in the file will be lost");
+            Ok(Config::default())
+        }
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_replaces_invalid_config() {
+        let invalid = Err(ErsaError::Config("Invalid config config.toml".to_string()));
+        let (config, lines) = crate::log::capture(|| replace_invalid(invalid));
+        assert_eq!(config.unwrap(), Config::default());
+        assert!(lines.iter().any(|l| l.contains("Invalid config")));
+
+        let unreadable = Err(ErsaError::Other("Failed to read config".to_string()));
+        assert!(replace_invalid(unreadable).is_err());
+    }
+}
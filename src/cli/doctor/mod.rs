@@ -0,0 +1,230 @@
+use crate::error::{ErsaError, Result};
+use clap::Args;
+use std::path::Path;
+use std::process::Command;
+
+#[derive(Args, Debug)]
+pub struct DoctorArgs {}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug)]
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+    hint: Option<&'static str>,
+}
+
+impl Check {
+    fn ok(name: &'static str, detail: String) -> Self {
+        Check {
+            name,
+            status: Status::Ok,
+            detail,
+            hint: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: String, hint: &'static str) -> Self {
+        Check {
+            name,
+            status: Status::Warn,
+            detail,
+            hint: Some(hint),
+        }
+    }
+
+    fn fail(name: &'static str, detail: String, hint: &'static str) -> Self {
+        Check {
+            name,
+            status: Status::Fail,
+            detail,
+            hint: Some(hint),
+        }
+    }
+}
+
+fn check_lsp(installed: bool, lsp_path: &Path) -> Check {
+    const NAME: &str = "LSP server";
+
+    if !installed {
+        return Check::warn(
+            NAME,
+            format!("not installed at {}", lsp_path.display()),
+            "Run `ersa lsp --install`",
+        );
+    }
+
+    match super::lsp::update::get_version_of(lsp_path) {
+        Ok(version) => Check::ok(NAME, format!("{} at {}", version, lsp_path.display())),
+        Err(e) => Check::fail(
+            NAME,
+            format!("installed but not runnable: {}", e),
+            "Reinstall it with `ersa lsp --install`",
+        ),
+    }
+}
+
+fn check_user_dir(user_dir: &Path) -> Check {
+    const NAME: &str = "User directory";
+
+    let probe = user_dir.join(".ersa-doctor");
+    let writable = std::fs::create_dir_all(user_dir)
+        .and_then(|_| std::fs::write(&probe, b""))
+        .and_then(|_| std::fs::remove_file(&probe));
+
+    match writable {
+        Ok(_) => Check::ok(NAME, format!("{} is writable", user_dir.display())),
+        Err(e) => Check::fail(
+            NAME,
+            format!("{} is not writable: {}", user_dir.display(), e),
+            "Check the permissions of the directory",
+        ),
+    }
+}
+
+fn check_git() -> Check {
+    const NAME: &str = "git";
+
+    match Command::new("git").arg("--version").output() {
+        Ok(output) if output.status.success() => Check::ok(
+            NAME,
+            String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        ),
+        _ => Check::warn(
+            NAME,
+            "not found on PATH".to_string(),
+            "Install git from https://git-scm.com",
+        ),
+    }
+}
+
+fn check_github_token(token: Option<String>) -> Check {
+    const NAME: &str = "GITHUB_TOKEN";
+
+    match token {
+        Some(token) if !token.trim().is_empty() => Check::ok(NAME, "set".to_string()),
+        _ => Check::warn(
+            NAME,
+            "not set".to_string(),
+            "Set GITHUB_TOKEN or github.token in the config to avoid GitHub API rate limits",
+        ),
+    }
+}
+
+fn check_project(dir: &Path, entry: &str) -> Check {
+    const NAME: &str = "Project";
+
+    let entry_path = dir.join(entry);
+    if entry_path.is_file() {
+        Check::ok(NAME, format!("entry file {}", entry_path.display()))
+    } else {
+        Check::warn(
+            NAME,
+            format!("no {} in {}", entry, dir.display()),
+            "Run ersa from a project directory or pass `-f` to build",
+        )
+    }
+}
+
+fn report(check: &Check) {
+    let line = format!("{}: {}", check.name, check.detail);
+    match check.status {
+        Status::Ok => crate::log::success(&line),
+        Status::Warn => crate::log::warn(&line),
+        Status::Fail => crate::log::error(&line),
+    }
+    if let Some(hint) = check.hint {
+        crate::log::info(&format!("  hint: {}", hint));
+    }
+}
+
+pub async fn run(_args: DoctorArgs) -> Result<()> {
+    let cwd = std::env::current_dir().map_err(ErsaError::io("Failed to get current directory"))?;
+
+    let config = crate::config::get();
+
+    let checks = [
+        check_lsp(
+            super::lsp::install::is_installed(),
+            &crate::paths::lsp_path(),
+        ),
+        check_user_dir(&crate::paths::user_dir()),
+        check_git(),
+        check_github_token(config.github_token()),
+        match config.build_entry(None) {
+            Ok(entry) => check_project(&cwd, &entry),
+            Err(e) => Check::fail(
+                "Project",
+                e.to_string(),
+                "Fix the entry with `ersa config set build.entry <path>`",
+            ),
+        },
+    ];
+
+    for check in &checks {
+        report(check);
+    }
+
+    let failed = checks.iter().filter(|c| c.status == Status::Fail).count();
+    if failed > 0 {
+        return Err(ErsaError::Other(format!("{} check(s) failed", failed)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ersa_doctor_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_check_lsp_missing() {
+        let dir = temp_dir("lsp");
+        assert_eq!(check_lsp(false, &dir.join("ersa_lsp")).status, Status::Warn);
+        std::fs::write(dir.join("ersa_lsp"), b"").unwrap();
+        assert_eq!(check_lsp(true, &dir.join("ersa_lsp")).status, Status::Fail);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_user_dir() {
+        let dir = temp_dir("user");
+        assert_eq!(check_user_dir(&dir.join("nested")).status, Status::Ok);
+        assert!(!dir.join("nested").join(".ersa-doctor").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_github_token() {
+        assert_eq!(
+            check_github_token(Some("ghp_abc".to_string())).status,
+            Status::Ok
+        );
+        assert_eq!(
+            check_github_token(Some(" ".to_string())).status,
+            Status::Warn
+        );
+        assert_eq!(check_github_token(None).status, Status::Warn);
+    }
+
+    #[test]
+    fn test_check_project() {
+        let dir = temp_dir("project");
+        assert_eq!(check_project(&dir, "main.gpc").status, Status::Warn);
+        std::fs::write(dir.join("main.gpc"), "main {}").unwrap();
+        assert_eq!(check_project(&dir, "main.gpc").status, Status::Ok);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+use crate::error::{ErsaError, Result};
+use clap::Args;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::build::preprocess::imports;
+
+#[derive(Args, Debug)]
+pub struct GraphArgs {
+    /// Entry file (defaults to the configured entry, main.gpc)
+    #[arg(long, short = 'f')]
+    file: Option<String>,
+
+    /// Print an ASCII tree instead of Graphviz DOT
+    #[arg(long)]
+    tree: bool,
+
+    /// Write the graph to a file instead of stdout
+    #[arg(long, short = 'o')]
+    output: Option<String>,
+
+    /// Also search this directory for imports (can be repeated)
+    #[arg(long = "include", short = 'I', value_name = "DIR")]
+    include_dirs: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Edge {
+    from: PathBuf,
+    to: PathBuf,
+    /// The target is already on the import chain leading to `from`
+    cycle: bool,
+}
+
+#[derive(Debug)]
+struct ImportGraph {
+    root: PathBuf,
+    edges: Vec<Edge>,
+    /// Searched for imports not found next to the importing file
+    include_dirs: Vec<PathBuf>,
+}
+
+impl ImportGraph {
+    /// Walk the imports reachable from `entry`
+    fn build(entry: &Path, include_dirs: &[PathBuf]) -> Result<Self> {
+        let root = entry.canonicalize().map_err(ErsaError::io(format!(
+            "Failed to resolve {}",
+            entry.display()
+        )))?;
+
+        let mut graph = ImportGraph {
+            root: root.clone(),
+            edges: Vec::new(),
+            include_dirs: include_dirs.to_vec(),
+        };
+        let mut walked = HashSet::new();
+        let mut stack = Vec::new();
+        graph.walk(&root, &mut walked, &mut stack)?;
+
+        Ok(graph)
+    }
+
+    fn walk(
+        &mut self,
+        file: &Path,
+        walked: &mut HashSet<PathBuf>,
+        stack: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        walked.insert(file.to_path_buf());
+        stack.push(file.to_path_buf());
+
+        let code = fs::read_to_string(file).map_err(ErsaError::io(format!(
+            "Failed to read '{}'",
+            file.display()
+        )))?;
+        let base = file.parent().ok_or_else(|| {
+            ErsaError::Other(format!(
+                "Failed to get parent directory of {}",
+                file.display()
+            ))
+        })?;
+
+        for import in imports::find_imports(&code)? {
+            // Embedded text files aren't code and import nothing
+            if import.text_alias.is_some() {
+                continue;
+            }
+            let target = match imports::resolve_import(&import.path, base, &self.include_dirs) {
+                Ok(target) => target,
+                Err(ErsaError::ImportNotFound { .. }) if import.optional => continue,
+                Err(e) => return Err(e),
+            };
+            let cycle = stack.contains(&target);
+
+            self.edges.push(Edge {
+                from: file.to_path_buf(),
+                to: target.clone(),
+                cycle,
+            });
+
+            if !cycle && !walked.contains(&target) {
+                self.walk(&target, walked, stack)?;
+            }
+        }
+
+        stack.pop();
+        Ok(())
+    }
+
+    /// Display a path relative to the entry file's directory
+    fn label(&self, path: &Path) -> String {
+        let base = self.root.parent().unwrap_or(Path::new(""));
+        path.strip_prefix(base)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .replace('\\', "/")
+    }
+
+    fn to_dot(&self) -> String {
+        let mut out = String::from("digraph imports {\n");
+        out.push_str(&format!("    \"{}\";\n", self.label(&self.root)));
+
+        for edge in &self.edges {
+            out.push_str(&format!(
+                "    \"{}\" -> \"{}\"",
+                self.label(&edge.from),
+                self.label(&edge.to)
+            ));
+            if edge.cycle {
+                out.push_str(" [color=red, label=\"cycle\"]");
+            }
+            out.push_str(";\n");
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    fn to_tree(&self) -> String {
+        let mut out = format!("{}\n", self.label(&self.root));
+        let mut shown = HashSet::from([self.root.clone()]);
+        self.write_tree(&self.root, "", &mut shown, &mut out);
+        out
+    }
+
+    fn write_tree(
+        &self,
+        node: &Path,
+        prefix: &str,
+        shown: &mut HashSet<PathBuf>,
+        out: &mut String,
+    ) {
+        let children: Vec<&Edge> = self.edges.iter().filter(|e| e.from == node).collect();
+
+        for (i, edge) in children.iter().enumerate() {
+            let last = i + 1 == children.len();
+            let branch = if last { "`-- " } else { "|-- " };
+            let note = if edge.cycle {
+                " (cycle)"
+            } else if shown.contains(&edge.to) {
+                " (already shown)"
+            } else {
+                ""
+            };
+
+            out.push_str(&format!(
+                "{}{}{}{}\n",
+                prefix,
+                branch,
+                self.label(&edge.to),
+                note
+            ));
+
+            if note.is_empty() {
+                shown.insert(edge.to.clone());
+                let child_prefix = format!("{}{}", prefix, if last { "    " } else { "|   " });
+                self.write_tree(&edge.to, &child_prefix, shown, out);
+            }
+        }
+    }
+}
+
+pub async fn run(args: GraphArgs) -> Result<()> {
+    let cwd = std::env::current_dir().map_err(ErsaError::io("Failed to get current directory"))?;
+    let entry = cwd.join(crate::config::get().build_entry(args.file)?);
+
+    if !entry.exists() {
+        return Err(ErsaError::FileNotFound(entry));
+    }
+
+    let include_dirs: Vec<PathBuf> = args.include_dirs.iter().map(|d| cwd.join(d)).collect();
+    let graph = ImportGraph::build(&entry, &include_dirs)?;
+    let rendered = if args.tree {
+        graph.to_tree()
+    } else {
+        graph.to_dot()
+    };
+
+    if let Some(output) = args.output {
+        fs::write(&output, rendered)
+            .map_err(ErsaError::io(format!("Failed to write {}", output)))?;
+        crate::log::success(&format!("Import graph written to {}", output));
+    } else {
+        print!("{}", rendered);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// main imports a and lib/b, a imports lib/b, lib/b imports a (cycle)
+    fn fixture(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ersa_graph_{}_{}", name, std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(
+            dir.join("main.gpc"),
+            "import a;\nimport \"lib/b\";\nmain {}",
+        )
+        .unwrap();
+        fs::write(dir.join("a.gpc"), "import lib/b;").unwrap();
+        fs::write(dir.join("lib").join("b.gpc"), "import ../a;").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_dot_output() {
+        let dir = fixture("dot");
+        let dot = ImportGraph::build(&dir.join("main.gpc"), &[])
+            .unwrap()
+            .to_dot();
+
+        assert!(dot.starts_with("digraph imports {"));
+        assert!(dot.contains("\"main.gpc\" -> \"a.gpc\";"));
+        assert!(dot.contains("\"main.gpc\" -> \"lib/b.gpc\";"));
+        assert!(dot.contains("\"a.gpc\" -> \"lib/b.gpc\";"));
+        assert!(dot.contains("\"lib/b.gpc\" -> \"a.gpc\" [color=red, label=\"cycle\"];"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tree_output() {
+        let dir = fixture("tree");
+        let tree = ImportGraph::build(&dir.join("main.gpc"), &[])
+            .unwrap()
+            .to_tree();
+
+        assert_eq!(
+            tree,
+            "main.gpc\n\
+             |-- a.gpc\n\
+             |   `-- lib/b.gpc\n\
+             |       `-- a.gpc (cycle)\n\
+             `-- lib/b.gpc (already shown)\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_dirs() {
+        let dir = std::env::temp_dir().join(format!("ersa_graph_include_{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("vendor")).unwrap();
+        fs::write(dir.join("src").join("main.gpc"), "import util;\nmain {}").unwrap();
+        fs::write(dir.join("vendor").join("util.gpc"), "").unwrap();
+
+        let entry = dir.join("src").join("main.gpc");
+        assert!(matches!(
+            ImportGraph::build(&entry, &[]),
+            Err(ErsaError::ImportNotFound { .. })
+        ));
+
+        let vendor = dir.join("vendor").canonicalize().unwrap();
+        let graph = ImportGraph::build(&entry, std::slice::from_ref(&vendor)).unwrap();
+        assert_eq!(graph.edges.len(), 1);
+        assert_eq!(graph.edges[0].to, vendor.join("util.gpc"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,45 +1,50 @@
-use std::path::PathBuf;
-
-const REPO_API_URL: &str = "https://api.github.com/repos/zKiwiko/ersa-lsp-core/releases/latest";
-
-#[cfg(target_os = "windows")]
-const LSP_BINARY_NAME: &str = "ersa_lsp.exe";
-#[cfg(not(target_os = "windows"))]
-const LSP_BINARY_NAME: &str = "ersa_lsp";
-
-/// Get the path where the LSP binary should be installed
-pub fn get_lsp_path() -> PathBuf {
-    let user_dir = if cfg!(target_os = "windows") {
-        PathBuf::from(
-            std::env::var("APPDATA")
-                .unwrap_or_else(|_| String::from("C:\\Users\\Default\\AppData\\Roaming")),
-        )
-        .join("ersa")
-    } else {
-        PathBuf::from(std::env::var("HOME").unwrap_or_else(|_| String::from("/tmp")))
-            .join(".local/share/ersa")
-    };
-
-    user_dir.join(LSP_BINARY_NAME)
-}
+use crate::error::{ErsaError, Result};
+
+const REPO: &str = "zKiwiko/ersa-lsp-core";
 
 /// Check if the LSP is currently installed
-#[allow(dead_code)]
 pub fn is_installed() -> bool {
-    get_lsp_path().exists()
+    crate::paths::lsp_path().exists()
 }
 
 /// Install the LSP server
-pub async fn install() -> Result<(), String> {
+pub async fn install() -> Result<()> {
     crate::log::info("Installing LSP server...");
 
-    crate::network::download_latest_release(REPO_API_URL)
+    if crate::cli::is_dry_run() {
+        crate::log::info(&format!(
+            "Dry run: would install the latest LSP server to {}",
+            crate::paths::lsp_path().display()
+        ));
+        return Ok(());
+    }
+
+    crate::network::download_latest_release(&crate::network::github::latest_release_url(REPO))
         .await
-        .map_err(|e| format!("Failed to download LSP: {}", e))?;
+        .map_err(|e| ErsaError::Network(format!("Failed to download LSP: {}", e)))?;
 
     crate::log::info(&format!(
         "LSP installed successfully at: {}",
-        get_lsp_path().display()
+        crate::paths::lsp_path().display()
     ));
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dry_run_install_does_nothing() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        crate::cli::set_dry_run(true);
+        let (result, lines) = crate::log::capture(|| runtime.block_on(install()));
+        crate::cli::set_dry_run(false);
+
+        assert!(result.is_ok());
+        assert!(lines.iter().any(|l| l.contains("Dry run: would install")));
+    }
+}
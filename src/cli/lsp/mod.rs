@@ -1,7 +1,8 @@
+use crate::error::{ErsaError, Result};
 use clap::Args;
 
-mod install;
-mod update;
+pub(crate) mod install;
+pub(crate) mod update;
 
 #[derive(Args, Debug)]
 pub struct LspArgs {
@@ -18,7 +19,7 @@ pub struct LspArgs {
     pub check_update: bool,
 }
 
-pub async fn run(args: LspArgs) -> Result<(), String> {
+pub async fn run(args: LspArgs) -> Result<()> {
     if args.install {
         install::install().await
     } else if args.update {
@@ -26,9 +27,9 @@ pub async fn run(args: LspArgs) -> Result<(), String> {
     } else if args.check_update {
         update::check_update().await.map(|_| ())
     } else {
-        Err(
+        Err(ErsaError::Other(
             "No valid LSP command provided. Use --install, --update, or --check-update."
                 .to_string(),
-        )
+        ))
     }
 }
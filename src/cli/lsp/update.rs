@@ -1,53 +1,25 @@
+use crate::error::{ErsaError, Result};
+use crate::network::version::Version;
+use std::path::Path;
 use std::process::Command;
 
-const REPO_API_URL: &str = "https://api.github.com/repos/zKiwiko/ersa-lsp-core/releases/latest";
-
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
-struct Version {
-    major: u32,
-    minor: u32,
-    patch: u32,
-}
-
-impl Version {
-    fn parse(s: &str) -> Result<Self, String> {
-        let s = s.trim().trim_start_matches('v');
-        let parts: Vec<&str> = s.split('.').collect();
-
-        if parts.len() != 3 {
-            return Err(format!("Invalid version format: {}", s));
-        }
-
-        let major = parts[0]
-            .parse()
-            .map_err(|_| format!("Invalid major version: {}", parts[0]))?;
-        let minor = parts[1]
-            .parse()
-            .map_err(|_| format!("Invalid minor version: {}", parts[1]))?;
-        let patch = parts[2]
-            .parse()
-            .map_err(|_| format!("Invalid patch version: {}", parts[2]))?;
-
-        Ok(Version {
-            major,
-            minor,
-            patch,
-        })
-    }
-}
+const REPO: &str = "zKiwiko/ersa-lsp-core";
 
 /// Get the currently installed version by running `ersa_lsp --version`
-fn get_installed_version() -> Result<Version, String> {
-    let lsp_path = super::install::get_lsp_path();
+fn get_installed_version() -> Result<Version> {
+    get_version_of(&crate::paths::lsp_path())
+}
 
+/// Get the version of the LSP binary at `lsp_path`
+pub(crate) fn get_version_of(lsp_path: &Path) -> Result<Version> {
     if !lsp_path.exists() {
-        return Err("LSP not installed".to_string());
+        return Err(ErsaError::FileNotFound(lsp_path.to_path_buf()));
     }
 
-    let output = Command::new(&lsp_path)
+    let output = Command::new(lsp_path)
         .arg("--version")
         .output()
-        .map_err(|e| format!("Failed to run LSP binary: {}", e))?;
+        .map_err(|e| ErsaError::Command(format!("Failed to run LSP binary: {}", e)))?;
 
     let version_str = String::from_utf8_lossy(&output.stdout);
     let version_str = version_str.trim();
@@ -56,25 +28,20 @@ fn get_installed_version() -> Result<Version, String> {
 }
 
 /// Check for updates and return true if an update is available
-pub async fn check_update() -> Result<bool, String> {
+pub async fn check_update() -> Result<bool> {
     crate::log::info("Checking for LSP server updates...");
 
-    let latest_version_str = crate::network::get_latest_version(REPO_API_URL)
-        .await
-        .map_err(|e| format!("Failed to fetch latest version: {}", e))?;
+    let latest_version_str =
+        crate::network::get_latest_version(&crate::network::github::latest_release_url(REPO))
+            .await
+            .map_err(|e| ErsaError::Network(format!("Failed to fetch latest version: {}", e)))?;
 
     let latest_version = Version::parse(&latest_version_str)?;
 
     match get_installed_version() {
         Ok(installed_version) => {
-            crate::log::info(&format!(
-                "Installed version: {}.{}.{}",
-                installed_version.major, installed_version.minor, installed_version.patch
-            ));
-            crate::log::info(&format!(
-                "Latest version: {}.{}.{}",
-                latest_version.major, latest_version.minor, latest_version.patch
-            ));
+            crate::log::info(&format!("Installed version: {}", installed_version));
+            crate::log::info(&format!("Latest version: {}", latest_version));
 
             if latest_version > installed_version {
                 crate::log::info("Update available!");
@@ -92,13 +59,21 @@ pub async fn check_update() -> Result<bool, String> {
 }
 
 /// Update the LSP server to the latest version
-pub async fn update() -> Result<(), String> {
+pub async fn update() -> Result<()> {
     crate::log::info("Updating LSP server...");
 
+    if crate::cli::is_dry_run() {
+        crate::log::info(&format!(
+            "Dry run: would replace {} with the latest release",
+            crate::paths::lsp_path().display()
+        ));
+        return Ok(());
+    }
+
     // Download and replace the binary
-    crate::network::download_latest_release(REPO_API_URL)
+    crate::network::download_latest_release(&crate::network::github::latest_release_url(REPO))
         .await
-        .map_err(|e| format!("Failed to download update: {}", e))?;
+        .map_err(|e| ErsaError::Network(format!("Failed to download update: {}", e)))?;
 
     crate::log::info("LSP updated successfully!");
     Ok(())
@@ -0,0 +1,135 @@
+use crate::error::{ErsaError, Result};
+use clap::{Args, Subcommand};
+use std::fs;
+use std::path::Path;
+
+use super::build::preprocess::{self, Pass, Passes, PreprocessOptions, macros};
+
+#[derive(Args, Debug)]
+pub struct MacroArgs {
+    #[command(subcommand)]
+    pub command: MacroCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MacroCommand {
+    /// Run only the macro passes on a file (`//!` comments, `env!` and
+    /// macros), without imports or optimization
+    Expand {
+        file: String,
+
+        /// Write the result to a file instead of stdout
+        #[arg(long, short = 'o')]
+        output: Option<String>,
+
+        /// Define a macro, e.g. `-D DEBUG` or `-D MAX=100`
+        #[arg(long = "define", short = 'D', value_name = "NAME[=VALUE]")]
+        defines: Vec<String>,
+    },
+}
+
+pub async fn run(args: MacroArgs) -> Result<()> {
+    match args.command {
+        MacroCommand::Expand {
+            file,
+            output,
+            defines,
+        } => {
+            let options = PreprocessOptions {
+                defines: defines
+                    .iter()
+                    .map(|d| macros::parse_define(d))
+                    .collect::<Result<Vec<_>>>()?,
+                passes: Passes::only(&[Pass::Macros]),
+                ..Default::default()
+            };
+            let expanded = expand_file(Path::new(&file), &options)?;
+
+            if let Some(output) = output {
+                fs::write(&output, expanded)
+                    .map_err(ErsaError::io(format!("Failed to write {}", output)))?;
+                crate::log::success(&format!("Expanded macros written to {}", output));
+            } else {
+                print!("{}", expanded);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Read `path` and run the passes selected in `options` on it, as the build
+/// would, logging any warnings
+fn expand_file(path: &Path, options: &PreprocessOptions) -> Result<String> {
+    if !path.exists() {
+        return Err(ErsaError::FileNotFound(path.to_path_buf()));
+    }
+
+    let code = fs::read_to_string(path)
+        .map_err(ErsaError::io(format!("Failed to read {}", path.display())))?;
+
+    let mut warnings = Vec::new();
+    let code = preprocess::strip_bom(&code);
+    let base_path = path.parent().unwrap_or(Path::new("."));
+    let expanded = preprocess::preprocess(code, base_path, options, &mut warnings)?;
+    for warning in &warnings {
+        crate::log::warn(warning);
+    }
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_file() {
+        let dir = std::env::temp_dir().join(format!("ersa_macro_expand_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("main.gpc");
+        fs::write(
+            &file,
+            "import lib;\r\ndefine! twice(v) { v + v }\r\nmain {\r\n    //! build only\r\n    x = twice(2)!;\r\n}\r\n",
+        )
+        .unwrap();
+
+        let options = PreprocessOptions {
+            passes: Passes::only(&[Pass::Macros]),
+            ..Default::default()
+        };
+        let expanded = expand_file(&file, &options).unwrap();
+        assert_eq!(
+            expanded,
+            "import lib;\r\n\r\nmain {\r\n    x = 2 + 2;\r\n}\r\n"
+        );
+
+        assert!(matches!(
+            expand_file(&dir.join("missing.gpc"), &options),
+            Err(ErsaError::FileNotFound(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expand_file_with_env() {
+        let dir = std::env::temp_dir().join(format!("ersa_macro_env_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("main.gpc");
+        fs::write(
+            &file,
+            "define! PATH_OF { env!(\"PATH\") }\nmain { p = PATH_OF!; }\n",
+        )
+        .unwrap();
+
+        let options = PreprocessOptions {
+            passes: Passes::only(&[Pass::Macros]),
+            ..Default::default()
+        };
+        let expanded = expand_file(&file, &options).unwrap();
+        let path = std::env::var("PATH").unwrap();
+        assert!(expanded.contains(&format!("p = {};", path)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
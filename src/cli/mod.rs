@@ -1,17 +1,123 @@
+use crate::error::{ErsaError, Result};
 use clap::Subcommand;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 pub mod build;
+pub mod config;
+pub mod doctor;
+pub mod graph;
 pub mod lsp;
+pub mod macros;
+pub mod run;
+pub mod self_update;
+pub mod version;
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// Make commands that overwrite files report what they would do instead
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// Make commands fail if they logged any warnings
+pub fn set_strict(strict: bool) {
+    STRICT.store(strict, Ordering::Relaxed);
+}
+
+pub fn is_strict() -> bool {
+    STRICT.load(Ordering::Relaxed)
+}
 
 #[derive(Subcommand, Debug)]
 pub enum Command {
     Lsp(self::lsp::LspArgs),
     Build(self::build::BuildArgs),
+    Run(self::run::RunArgs),
+    Doctor(self::doctor::DoctorArgs),
+    Graph(self::graph::GraphArgs),
+    Config(self::config::ConfigArgs),
+    #[command(name = "macro")]
+    Macro(self::macros::MacroArgs),
+    #[command(name = "self")]
+    SelfUpdate(self::self_update::SelfArgs),
+}
+
+pub async fn run(command: Command) -> Result<()> {
+    let warnings_before = crate::log::warning_count();
+    let result = run_command(command).await;
+    check_strict(
+        result,
+        is_strict(),
+        crate::log::warning_count() - warnings_before,
+    )
 }
 
-pub async fn run(command: Command) -> Result<(), String> {
+/// Turn a successful run into an error if `strict` and any warnings were logged
+fn check_strict(result: Result<()>, strict: bool, warnings: usize) -> Result<()> {
+    match result {
+        Ok(()) if strict && warnings > 0 => Err(ErsaError::Strict(warnings)),
+        other => other,
+    }
+}
+
+async fn run_command(command: Command) -> Result<()> {
     match command {
         Command::Lsp(args) => self::lsp::run(args).await,
         Command::Build(args) => self::build::run(args).await,
+        Command::Run(args) => self::run::run(args).await,
+        Command::Doctor(args) => self::doctor::run(args).await,
+        Command::Graph(args) => self::graph::run(args).await,
+        Command::Config(args) => self::config::run(args).await,
+        Command::Macro(args) => self::macros::run(args).await,
+        Command::SelfUpdate(args) => self::self_update::run(args).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        build: build::BuildArgs,
+    }
+
+    #[test]
+    fn test_strict_fails_warning_build() {
+        let dir = std::env::temp_dir().join(format!("ersa_strict_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let entry = dir.join("main.gpc");
+        let output = dir.join("out.gpc");
+        std::fs::write(&entry, "main { x = 1 / 0; }\n").unwrap();
+
+        let args = TestCli::parse_from([
+            "test",
+            "-f",
+            entry.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+        ])
+        .build;
+
+        let warnings_before = crate::log::warning_count();
+        let (result, lines) = crate::log::capture(|| build::build(args).map(|_| ()));
+        let warnings = crate::log::warning_count() - warnings_before;
+
+        assert!(lines.iter().any(|l| l.contains("Division by zero")));
+        assert!(warnings >= 1);
+
+        let err = check_strict(result, true, warnings).unwrap_err();
+        assert!(matches!(err, ErsaError::Strict(n) if n >= 1));
+        assert!(check_strict(Ok(()), false, warnings).is_ok());
+        assert!(check_strict(Ok(()), true, 0).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
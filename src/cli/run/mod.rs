@@ -0,0 +1,223 @@
+use crate::error::{ErsaError, Result};
+use clap::{ArgGroup, Args};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+#[derive(Args, Debug)]
+#[command(group(ArgGroup::new("action").args(["copy", "open", "with"])))]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub build: super::build::BuildArgs,
+
+    /// Copy the built output to the clipboard (default)
+    #[arg(long)]
+    pub copy: bool,
+
+    /// Open the built output with the system's default program
+    #[arg(long)]
+    pub open: bool,
+
+    /// Run a program with the path of the built output as its argument
+    #[arg(long, value_name = "PROGRAM")]
+    pub with: Option<String>,
+}
+
+pub async fn run(args: RunArgs) -> Result<()> {
+    if let Some(flag) = super::build::unsupported_with_run(&args.build) {
+        return Err(ErsaError::Other(format!(
+            "{} can't be used with `ersa run`",
+            flag
+        )));
+    }
+
+    let open = args.open;
+    let with = args.with.clone();
+
+    let output_path = super::build::build(args.build)?;
+
+    if let Some(program) = with {
+        run_program(&program, &output_path)
+    } else if open {
+        open_file(&output_path)
+    } else {
+        copy_to_clipboard(&output_path)
+    }
+}
+
+/// Run `program` with the output path as its only argument
+fn run_program(program: &str, path: &Path) -> Result<()> {
+    crate::log::info(&format!("Running {} {}", program, path.display()));
+
+    let status = Command::new(program)
+        .arg(path)
+        .status()
+        .map_err(|e| ErsaError::Command(format!("Failed to run '{}': {}", program, e)))?;
+
+    if !status.success() {
+        return Err(ErsaError::Command(format!(
+            "'{}' exited with {}",
+            program, status
+        )));
+    }
+    Ok(())
+}
+
+/// Open the output with the platform's default handler
+fn open_file(path: &Path) -> Result<()> {
+    let mut command = if cfg!(target_os = "windows") {
+        let mut command = Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else {
+        Command::new("xdg-open")
+    };
+
+    let status = command
+        .arg(path)
+        .status()
+        .map_err(|e| ErsaError::Command(format!("Failed to open {}: {}", path.display(), e)))?;
+
+    if !status.success() {
+        return Err(ErsaError::Command(format!(
+            "Failed to open {}: {}",
+            path.display(),
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Copy the contents of the output to the clipboard
+fn copy_to_clipboard(path: &Path) -> Result<()> {
+    let code =
+        std::fs::read_to_string(path).map_err(ErsaError::io("Failed to read output file"))?;
+
+    let candidates: &[(&str, &[&str])] = if cfg!(target_os = "windows") {
+        &[("clip", &[])]
+    } else if cfg!(target_os = "macos") {
+        &[("pbcopy", &[])]
+    } else {
+        &[
+            ("wl-copy", &[]),
+            ("xclip", &["-selection", "clipboard"]),
+            ("xsel", &["--clipboard", "--input"]),
+        ]
+    };
+
+    let program = pipe_to_first(candidates, code.as_bytes())?;
+    crate::log::vinfo(&format!("Copied with {}", program));
+    crate::log::success("Build output copied to clipboard");
+    Ok(())
+}
+
+/// Write `input` to the first of `candidates` that runs and exits
+/// successfully, returning its name. A program that is missing, fails to read
+/// its input or exits with an error is skipped.
+fn pipe_to_first<'a>(candidates: &[(&'a str, &[&str])], input: &[u8]) -> Result<&'a str> {
+    for (program, program_args) in candidates {
+        let Ok(mut child) = Command::new(program)
+            .args(*program_args)
+            .stdin(Stdio::piped())
+            .spawn()
+        else {
+            continue;
+        };
+
+        // Closing stdin lets the program see the end of its input
+        let written = child
+            .stdin
+            .take()
+            .is_some_and(|mut stdin| stdin.write_all(input).is_ok());
+        match child.wait() {
+            Ok(status) if written && status.success() => return Ok(program),
+            Ok(status) => crate::log::vwarn(&format!("{} failed ({})", program, status)),
+            Err(e) => crate::log::vwarn(&format!("Failed to wait for {}: {}", program, e)),
+        }
+    }
+
+    Err(ErsaError::Command(
+        "No clipboard program succeeded. Use --open or --with instead.".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        args: RunArgs,
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_clipboard_falls_through_failures() {
+        let candidates: &[(&str, &[&str])] = &[
+            ("ersa-no-such-clipboard", &[]),
+            ("false", &[]),
+            ("sh", &["-c", "cat > /dev/null"]),
+        ];
+        assert_eq!(pipe_to_first(candidates, b"main {}").unwrap(), "sh");
+
+        let err = pipe_to_first(&candidates[..2], b"main {}").unwrap_err();
+        assert!(matches!(err, ErsaError::Command(_)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_with_program() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("ersa_run_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let input = dir.join("main.gpc");
+        let output = dir.join("build").join("build.gpc");
+        let record = dir.join("record.txt");
+        std::fs::write(&input, "main { x = 1 + 2; }").unwrap();
+
+        let script = dir.join("record.sh");
+        std::fs::write(
+            &script,
+            format!("#!/bin/sh\necho \"$1\" > {}\n", record.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let cli = TestCli::parse_from([
+            "ersa",
+            "-f",
+            input.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+            "--with",
+            script.to_str().unwrap(),
+        ]);
+        run(cli.args).await.unwrap();
+
+        assert!(std::fs::read_to_string(&output).unwrap().contains("3;"));
+        assert_eq!(
+            std::fs::read_to_string(&record).unwrap().trim(),
+            output.to_str().unwrap()
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_rejects_watch() {
+        let cli = TestCli::parse_from(["ersa", "--watch", "--clear"]);
+        let err = run(cli.args).await.unwrap_err();
+        assert!(matches!(err, ErsaError::Other(ref msg) if msg.contains("--watch")));
+    }
+
+    #[test]
+    fn test_actions_are_exclusive() {
+        assert!(TestCli::try_parse_from(["ersa", "--copy", "--open"]).is_err());
+    }
+}
@@ -0,0 +1,22 @@
+use crate::error::Result;
+use clap::{Args, Subcommand};
+
+mod update;
+
+#[derive(Args, Debug)]
+pub struct SelfArgs {
+    #[command(subcommand)]
+    pub command: SelfCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SelfCommand {
+    /// Update ersa to the latest release
+    Update,
+}
+
+pub async fn run(args: SelfArgs) -> Result<()> {
+    match args.command {
+        SelfCommand::Update => update::update().await,
+    }
+}
@@ -0,0 +1,272 @@
+use crate::error::{ErsaError, Result};
+use crate::network::version::Version;
+use std::io::Read;
+use std::path::Path;
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+const REPO: &str = "zKiwiko/ersa";
+
+/// Get the release archive name for the given platform, as published by
+/// cargo-dist, e.g. `ersa-x86_64-unknown-linux-gnu.tar.xz`
+fn asset_name_for(os: &str, arch: &str) -> Option<String> {
+    match os {
+        "linux" => Some(format!("ersa-{}-unknown-linux-gnu.tar.xz", arch)),
+        "macos" => Some(format!("ersa-{}-apple-darwin.tar.xz", arch)),
+        "windows" => Some(format!("ersa-{}-pc-windows-msvc.zip", arch)),
+        _ => None,
+    }
+}
+
+/// Pull the ersa executable out of the release archive `asset_name`.
+///
+/// cargo-dist puts the binary next to the README and license, in a
+/// top-level directory for `.tar.xz` archives and at the root for `.zip`.
+fn extract_binary(asset_name: &str, archive: &[u8]) -> Result<Vec<u8>> {
+    let failed = |e: &dyn std::fmt::Display| {
+        ErsaError::Other(format!("Failed to extract {}: {}", asset_name, e))
+    };
+    let is_binary = |path: &Path, name: &str| path.file_name().is_some_and(|n| n == name);
+
+    let mut binary = Vec::new();
+    if asset_name.ends_with(".zip") {
+        let mut zip =
+            zip::ZipArchive::new(std::io::Cursor::new(archive)).map_err(|e| failed(&e))?;
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i).map_err(|e| failed(&e))?;
+            let path = file.enclosed_name();
+            if file.is_file() && path.is_some_and(|path| is_binary(&path, "ersa.exe")) {
+                file.read_to_end(&mut binary).map_err(|e| failed(&e))?;
+                return Ok(binary);
+            }
+        }
+    } else if asset_name.ends_with(".tar.xz") {
+        let mut tar = Vec::new();
+        lzma_rs::xz_decompress(&mut std::io::Cursor::new(archive), &mut tar)
+            .map_err(|e| failed(&e))?;
+        let mut tar = tar::Archive::new(tar.as_slice());
+        for entry in tar.entries().map_err(|e| failed(&e))? {
+            let mut entry = entry.map_err(|e| failed(&e))?;
+            let path = entry.path().map_err(|e| failed(&e))?.into_owned();
+            if entry.header().entry_type().is_file() && is_binary(&path, "ersa") {
+                entry.read_to_end(&mut binary).map_err(|e| failed(&e))?;
+                return Ok(binary);
+            }
+        }
+    } else {
+        return Err(failed(&"unknown archive format"));
+    }
+
+    Err(failed(&"no ersa executable in the archive"))
+}
+
+/// Get the release asset name for the running platform
+fn asset_name() -> Result<String> {
+    let os = std::env::consts::OS;
+    let arch = std::env::consts::ARCH;
+    asset_name_for(os, arch)
+        .ok_or_else(|| ErsaError::Other(format!("Unsupported platform: {}-{}", arch, os)))
+}
+
+/// Check whether `latest` is newer than `current`
+fn is_newer(current: &str, latest: &str) -> Result<bool> {
+    Ok(Version::parse(latest)? > Version::parse(current)?)
+}
+
+/// Replace the file at `target` with `bytes`.
+///
+/// The new contents are written next to the target and renamed over it so
+/// the replacement is atomic. Windows refuses to overwrite a running
+/// executable, so there the old file is first moved out of the way.
+fn replace_file(target: &Path, bytes: &[u8]) -> Result<()> {
+    let new_path = target.with_extension("new");
+    std::fs::write(&new_path, bytes).map_err(ErsaError::io("Failed to write file"))?;
+
+    #[cfg(unix)]
+    {
+        let mut perms = std::fs::metadata(&new_path)
+            .map_err(ErsaError::io("Failed to read permissions"))?
+            .permissions();
+        perms.set_mode(0o755);
+        std::fs::set_permissions(&new_path, perms)
+            .map_err(ErsaError::io("Failed to set permissions"))?;
+    }
+
+    if cfg!(target_os = "windows") {
+        let old_path = target.with_extension("old");
+        // Leftover from a previous update, the old binary is no longer running
+        let _ = std::fs::remove_file(&old_path);
+        std::fs::rename(target, &old_path)
+            .map_err(ErsaError::io("Failed to move old executable"))?;
+    }
+
+    std::fs::rename(&new_path, target).map_err(ErsaError::io("Failed to replace executable"))?;
+
+    Ok(())
+}
+
+/// Update the ersa executable to the latest release
+pub async fn update() -> Result<()> {
+    crate::log::info("Checking for ersa updates...");
+
+    let release = crate::network::get_release(&crate::network::github::latest_release_url(REPO))
+        .await
+        .map_err(|e| ErsaError::Network(format!("Failed to fetch latest release: {}", e)))?;
+    let latest_version = crate::network::release_tag(&release)?;
+    let current_version = env!("CARGO_PKG_VERSION");
+
+    crate::log::info(&format!("Installed version: {}", current_version));
+    crate::log::info(&format!("Latest version: {}", latest_version));
+
+    if !is_newer(current_version, &latest_version)? {
+        crate::log::info("ersa is up to date.");
+        return Ok(());
+    }
+
+    let asset_name = asset_name()?;
+    let download_url = crate::network::find_asset_url(&release, &asset_name)?;
+    let exe_path = std::env::current_exe()
+        .map_err(ErsaError::io("Failed to locate the running executable"))?;
+
+    if crate::cli::is_dry_run() {
+        crate::log::info(&format!(
+            "Dry run: would replace {} with {} from {}",
+            exe_path.display(),
+            asset_name,
+            download_url
+        ));
+        return Ok(());
+    }
+
+    crate::log::info(&format!("Downloading {}...", asset_name));
+    let archive = crate::network::download_bytes(download_url).await?;
+    let binary = extract_binary(&asset_name, &archive)?;
+
+    replace_file(&exe_path, &binary)?;
+
+    crate::log::success(&format!("ersa updated to {}", latest_version));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A release with the assets cargo-dist uploads
+    fn mock_release() -> serde_json::Value {
+        let asset = |name: &str| {
+            serde_json::json!({
+                "name": name,
+                "browser_download_url": format!("https://example.com/{}", name)
+            })
+        };
+        serde_json::json!({
+            "tag_name": "v0.2.0",
+            "assets": [
+                asset("dist-manifest.json"),
+                asset("ersa-installer.sh"),
+                asset("ersa-x86_64-unknown-linux-gnu.tar.xz"),
+                asset("ersa-x86_64-unknown-linux-gnu.tar.xz.sha256"),
+                asset("ersa-x86_64-pc-windows-msvc.zip"),
+                asset("ersa-x86_64-pc-windows-msvc.zip.sha256"),
+                asset("source.tar.gz")
+            ]
+        })
+    }
+
+    /// A `.tar.xz` laid out like cargo-dist's, with the files under `dir/`
+    fn mock_tar_xz(dir: &str, files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o755);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, format!("{}/{}", dir, name), *contents)
+                .unwrap();
+        }
+        let tar = builder.into_inner().unwrap();
+
+        let mut xz = Vec::new();
+        lzma_rs::xz_compress(&mut tar.as_slice(), &mut xz).unwrap();
+        xz
+    }
+
+    fn mock_zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        use std::io::Write;
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_extract_binary() {
+        let linux = "ersa-x86_64-unknown-linux-gnu.tar.xz";
+        let archive = mock_tar_xz(
+            "ersa-x86_64-unknown-linux-gnu",
+            &[("README.md", b"readme"), ("ersa", b"linux binary")],
+        );
+        assert_eq!(extract_binary(linux, &archive).unwrap(), b"linux binary");
+
+        let windows = "ersa-x86_64-pc-windows-msvc.zip";
+        let archive = mock_zip(&[("LICENSE", b"license"), ("ersa.exe", b"windows binary")]);
+        assert_eq!(
+            extract_binary(windows, &archive).unwrap(),
+            b"windows binary"
+        );
+
+        let archive = mock_tar_xz("ersa-x86_64-unknown-linux-gnu", &[("README.md", b"readme")]);
+        assert!(extract_binary(linux, &archive).is_err());
+        assert!(extract_binary(linux, b"not an archive").is_err());
+    }
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("0.1.0", "v0.2.0").unwrap());
+        assert!(!is_newer("0.2.0", "v0.2.0").unwrap());
+        assert!(!is_newer("1.0.0", "v0.9.9").unwrap());
+        assert!(is_newer("0.1.0", "garbage").is_err());
+    }
+
+    #[test]
+    fn test_asset_selection() {
+        let release = mock_release();
+
+        let linux = asset_name_for("linux", "x86_64").unwrap();
+        assert_eq!(
+            crate::network::find_asset_url(&release, &linux).unwrap(),
+            "https://example.com/ersa-x86_64-unknown-linux-gnu.tar.xz"
+        );
+
+        let windows = asset_name_for("windows", "x86_64").unwrap();
+        assert_eq!(
+            crate::network::find_asset_url(&release, &windows).unwrap(),
+            "https://example.com/ersa-x86_64-pc-windows-msvc.zip"
+        );
+
+        let macos = asset_name_for("macos", "aarch64").unwrap();
+        assert!(crate::network::find_asset_url(&release, &macos).is_err());
+
+        assert!(asset_name_for("plan9", "x86_64").is_none());
+    }
+
+    #[test]
+    fn test_replace_file() {
+        let dir = std::env::temp_dir().join(format!("ersa_self_update_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("ersa");
+        std::fs::write(&target, b"old").unwrap();
+
+        replace_file(&target, b"new").unwrap();
+
+        assert_eq!(std::fs::read(&target).unwrap(), b"new");
+        assert!(!target.with_extension("new").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,42 @@
+/// Build the text printed by `ersa --version`.
+///
+/// The verbose form also reports the commit ersa was built from and the
+/// installed LSP server version.
+pub fn version_report(verbose: bool) -> String {
+    let mut report = format!("ersa {}", env!("CARGO_PKG_VERSION"));
+
+    if verbose {
+        report.push_str(&format!("\ncommit: {}", env!("GIT_HASH")));
+
+        let lsp_path = crate::paths::lsp_path();
+        let lsp = if super::lsp::install::is_installed() {
+            match super::lsp::update::get_version_of(&lsp_path) {
+                Ok(version) => version.to_string(),
+                Err(e) => format!("unknown ({})", e),
+            }
+        } else {
+            "not installed".to_string()
+        };
+        report.push_str(&format!("\nlsp: {}", lsp));
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_report() {
+        assert_eq!(
+            version_report(false),
+            format!("ersa {}", env!("CARGO_PKG_VERSION"))
+        );
+
+        let verbose = version_report(true);
+        assert!(verbose.contains(env!("CARGO_PKG_VERSION")));
+        assert!(verbose.contains("\ncommit: "));
+        assert!(verbose.contains("\nlsp: "));
+    }
+}
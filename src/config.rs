@@ -0,0 +1,436 @@
+use crate::cli::build::target::TargetProfile;
+use crate::error::{ErsaError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::OnceLock;
+
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+const DEFAULT_ENTRY: &str = "main.gpc";
+const DEFAULT_OUTPUT: &str = "build/build.gpc";
+
+/// Keys accepted by `ersa config get/set`
+pub const KEYS: &[&str] = &["github.token", "github.host", "build.entry", "build.output"];
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Global settings read from `config.toml` in the ersa config directory.
+///
+/// Every value can be overridden by an environment variable, which in turn
+/// is overridden by the matching CLI flag.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub github: GithubConfig,
+    pub build: BuildConfig,
+    /// Device profiles for `build --target`, by name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub targets: BTreeMap<String, TargetProfile>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GithubConfig {
+    /// Token sent with GitHub API requests (env: `GITHUB_TOKEN`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+
+    /// GitHub Enterprise host to use instead of github.com (env: `ERSA_GITHUB_HOST`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BuildConfig {
+    /// Entry file relative to the current directory (env: `ERSA_ENTRY`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entry: Option<String>,
+
+    /// Output file relative to the current directory (env: `ERSA_OUTPUT`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+}
+
+impl Config {
+    /// Load the global config file, falling back to defaults if it doesn't exist
+    pub fn load() -> Result<Config> {
+        let path = crate::paths::config_path();
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let contents = std::fs::read_to_string(&path).map_err(ErsaError::io(format!(
+            "Failed to read config {}",
+            path.display()
+        )))?;
+        Self::parse(&contents)
+            .map_err(|e| ErsaError::Config(format!("Invalid config {}: {}", path.display(), e)))
+    }
+
+    pub fn parse(contents: &str) -> Result<Config> {
+        toml::from_str(contents).map_err(|e| ErsaError::Parse(e.to_string()))
+    }
+
+    /// Write the config to the global config file, creating it if absent
+    pub fn save(&self) -> Result<()> {
+        self.save_to(&crate::paths::config_path())
+    }
+
+    /// Write the config to `path`. It may hold `github.token`, so on Unix the
+    /// file is readable and writable by its owner only.
+    fn save_to(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(ErsaError::io("Failed to create config directory"))?;
+        }
+
+        let contents = toml::to_string_pretty(self)
+            .map_err(|e| ErsaError::Config(format!("Failed to serialize config: {}", e)))?;
+        let context = format!("Failed to write config {}", path.display());
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(path).map_err(ErsaError::io(context.clone()))?;
+
+        // The mode only applies to new files, so tighten an existing one too
+        #[cfg(unix)]
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .map_err(ErsaError::io(context.clone()))?;
+
+        file.write_all(contents.as_bytes())
+            .map_err(ErsaError::io(context))
+    }
+
+    /// Get the file value of a dotted key such as `github.token`
+    pub fn get_key(&self, key: &str) -> Result<Option<&str>> {
+        let value = match key {
+            "github.token" => &self.github.token,
+            "github.host" => &self.github.host,
+            "build.entry" => &self.build.entry,
+            "build.output" => &self.build.output,
+            _ => return Err(unknown_key(key)),
+        };
+        Ok(value.as_deref())
+    }
+
+    /// Set the file value of a dotted key such as `github.token`
+    pub fn set_key(&mut self, key: &str, value: &str) -> Result<()> {
+        let field = match key {
+            "github.token" => &mut self.github.token,
+            "github.host" => &mut self.github.host,
+            "build.entry" => &mut self.build.entry,
+            "build.output" => &mut self.build.output,
+            _ => return Err(unknown_key(key)),
+        };
+        *field = Some(value.to_string());
+        Ok(())
+    }
+
+    pub fn github_token(&self) -> Option<String> {
+        resolve(None, env("GITHUB_TOKEN"), self.github.token.clone())
+    }
+
+    pub fn github_host(&self) -> Option<String> {
+        resolve(None, env("ERSA_GITHUB_HOST"), self.github.host.clone())
+    }
+
+    pub fn build_entry(&self, cli: Option<String>) -> Result<String> {
+        let file = if cli.is_none() && env("ERSA_ENTRY").is_none() {
+            self.expanded_path("build.entry", &self.build.entry, env)?
+        } else {
+            None
+        };
+        Ok(resolve(cli, env("ERSA_ENTRY"), file).unwrap_or_else(|| DEFAULT_ENTRY.to_string()))
+    }
+
+    pub fn build_output(&self, cli: Option<String>) -> Result<String> {
+        let file = if cli.is_none() && env("ERSA_OUTPUT").is_none() {
+            self.expanded_path("build.output", &self.build.output, env)?
+        } else {
+            None
+        };
+        Ok(resolve(cli, env("ERSA_OUTPUT"), file).unwrap_or_else(|| DEFAULT_OUTPUT.to_string()))
+    }
+
+    /// The file value of the path setting `key` with `$VAR` and `${VAR}`
+    /// references replaced from `env`. `$$` is a literal `$`.
+    ///
+    /// Only path settings are expanded, and only when the file value is
+    /// actually used, so a bad reference fails just the commands that need it.
+    fn expanded_path(
+        &self,
+        key: &str,
+        value: &Option<String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<String>> {
+        value
+            .as_deref()
+            .map(|value| {
+                expand_vars(value, env)
+                    .map_err(|e| ErsaError::Config(format!("Invalid value for '{}': {}", key, e)))
+            })
+            .transpose()
+    }
+}
+
+fn unknown_key(key: &str) -> ErsaError {
+    ErsaError::Config(format!(
+        "Unknown config key '{}'. Known keys: {}",
+        key,
+        KEYS.join(", ")
+    ))
+}
+
+/// Replace `$NAME` and `${NAME}` with values from `env`, failing on undefined
+/// variables. A `$` not followed by a variable name is kept as is.
+fn expand_vars(value: &str, env: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        if ch != '$' {
+            result.push(ch);
+            continue;
+        }
+
+        let braced = chars.peek() == Some(&'{');
+        if braced {
+            chars.next();
+        } else if chars.peek() == Some(&'$') {
+            chars.next();
+            result.push('$');
+            continue;
+        }
+
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                name.push(c);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        if braced && chars.next() != Some('}') {
+            return Err(ErsaError::Config(format!(
+                "Unterminated '${{' in '{}'",
+                value
+            )));
+        }
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            if braced {
+                return Err(ErsaError::Config(format!(
+                    "Invalid variable name in '{}'",
+                    value
+                )));
+            }
+            result.push('$');
+            result.push_str(&name);
+            continue;
+        }
+
+        match env(&name) {
+            Some(expanded) => result.push_str(&expanded),
+            None => {
+                return Err(ErsaError::Config(format!(
+                    "Undefined environment variable '{}'",
+                    name
+                )));
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+/// Pick the highest-priority value: CLI flag, then environment, then config file
+fn resolve(cli: Option<String>, env: Option<String>, file: Option<String>) -> Option<String> {
+    cli.or(env).or(file)
+}
+
+/// Install the config loaded at startup
+pub fn init(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+/// The config loaded at startup, or the defaults if none was installed
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_precedence() {
+        let some = |s: &str| Some(s.to_string());
+
+        assert_eq!(resolve(some("cli"), some("env"), some("file")), some("cli"));
+        assert_eq!(resolve(None, some("env"), some("file")), some("env"));
+        assert_eq!(resolve(None, None, some("file")), some("file"));
+        assert_eq!(resolve(None, None, None), None);
+    }
+
+    #[test]
+    fn test_built_in_defaults() {
+        let config = Config::default();
+        assert_eq!(
+            config.build_entry(Some("src/app.gpc".to_string())).unwrap(),
+            "src/app.gpc"
+        );
+        assert_eq!(
+            config.build_output(Some("out.gpc".to_string())).unwrap(),
+            "out.gpc"
+        );
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config =
+            Config::parse("[github]\ntoken = \"ghp_abc\"\n\n[build]\noutput = \"dist/out.gpc\"\n")
+                .unwrap();
+
+        assert_eq!(config.github.token.as_deref(), Some("ghp_abc"));
+        assert_eq!(config.build.output.as_deref(), Some("dist/out.gpc"));
+        assert_eq!(config.build.entry, None);
+        assert_eq!(
+            config.build_output(Some("cli.gpc".to_string())).unwrap(),
+            "cli.gpc"
+        );
+
+        assert!(Config::parse("[build]\noutput = 5\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_targets() {
+        let config = Config::parse("[targets.tiny]\nmax_size = 512\n").unwrap();
+        assert_eq!(config.targets["tiny"].max_size, 512);
+        assert!(Config::parse("[targets.tiny]\n").is_err());
+    }
+
+    #[test]
+    fn test_set_get_round_trip() {
+        let mut config = Config::default();
+        for key in KEYS {
+            assert_eq!(config.get_key(key).unwrap(), None);
+            config.set_key(key, "value").unwrap();
+            assert_eq!(config.get_key(key).unwrap(), Some("value"));
+        }
+
+        let reparsed = Config::parse(&toml::to_string_pretty(&config).unwrap()).unwrap();
+        assert_eq!(reparsed, config);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_saved_config_is_private() {
+        let dir = std::env::temp_dir().join(format!("ersa_config_save_{}", std::process::id()));
+        let path = dir.join("config.toml");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, "").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let mut config = Config::default();
+        config.set_key("github.token", "ghp_secret").unwrap();
+        config.save_to(&path).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            Config::parse(&std::fs::read_to_string(&path).unwrap()).unwrap(),
+            config
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        config.save_to(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_key() {
+        let mut config = Config::default();
+        assert!(config.get_key("github.nope").is_err());
+        assert!(config.set_key("registry", "x").is_err());
+    }
+
+    fn mock_env(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/user".to_string()),
+            "PROJECT" => Some("game".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand_vars() {
+        assert_eq!(
+            expand_vars("$HOME/src/${PROJECT}_main.gpc", mock_env).unwrap(),
+            "/home/user/src/game_main.gpc"
+        );
+        assert_eq!(
+            expand_vars("cost$$5 $ 1$", mock_env).unwrap(),
+            "cost$5 $ 1$"
+        );
+        assert_eq!(expand_vars("no vars", mock_env).unwrap(), "no vars");
+        assert!(expand_vars("${HOME", mock_env).is_err());
+        assert!(expand_vars("${}", mock_env).is_err());
+    }
+
+    #[test]
+    fn test_expand_undefined_var() {
+        let err = expand_vars("$MISSING/out.gpc", mock_env).unwrap_err();
+        assert!(err.to_string().contains("MISSING"));
+
+        let mut config = Config::default();
+        config
+            .set_key("build.output", "${MISSING}/out.gpc")
+            .unwrap();
+        let err = config
+            .expanded_path("build.output", &config.build.output, mock_env)
+            .unwrap_err();
+        assert!(err.to_string().contains("build.output"));
+    }
+
+    #[test]
+    fn test_only_paths_are_expanded() {
+        let mut config = Config::default();
+        config.set_key("build.entry", "$HOME/main.gpc").unwrap();
+        config
+            .set_key("build.output", "$ERSA_TEST_UNSET_VAR/out.gpc")
+            .unwrap();
+        config.set_key("github.token", "tok$en").unwrap();
+
+        assert_eq!(
+            config
+                .expanded_path("build.entry", &config.build.entry, mock_env)
+                .unwrap()
+                .as_deref(),
+            Some("/home/user/main.gpc")
+        );
+        assert_eq!(config.github.token.as_deref(), Some("tok$en"));
+        assert_eq!(
+            config.get_key("build.entry").unwrap(),
+            Some("$HOME/main.gpc")
+        );
+        // A CLI value wins, so the broken file value is never expanded
+        assert_eq!(
+            config.build_output(Some("cli.gpc".to_string())).unwrap(),
+            "cli.gpc"
+        );
+    }
+}
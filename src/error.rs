@@ -0,0 +1,249 @@
+use std::path::PathBuf;
+
+pub type Result<T> = std::result::Result<T, ErsaError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ErsaError {
+    /// A filesystem operation failed
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A request failed or returned an error response
+    #[error("{0}")]
+    Network(String),
+
+    #[error("Not fetching {0}: network access is disabled (--offline)")]
+    Offline(String),
+
+    /// A response, version string or config file could not be parsed
+    #[error("{0}")]
+    Parse(String),
+
+    #[error("Input file not found: {}", .0.display())]
+    FileNotFound(PathBuf),
+
+    #[error(
+        "Failed to resolve import path '{path}' ({}): {source}",
+        describe_tried(tried)
+    )]
+    ImportNotFound {
+        path: String,
+        /// Every location that was looked at, in search order
+        tried: Vec<PathBuf>,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("{}:{line}:{column}: {message}", file.display())]
+    Lexical {
+        file: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+
+    #[error("Circular import detected: {}", .0.display())]
+    CircularImport(PathBuf),
+
+    #[error("Import limit reached: more than {0} files would be inlined (see --max-imports)")]
+    TooManyImports(usize),
+
+    /// Several imports failed under `--keep-going`
+    #[error(
+        "{} import(s) failed:{}",
+        .0.len(),
+        .0.iter().map(|e| format!("\n  {}", e)).collect::<String>()
+    )]
+    Imports(Vec<ErsaError>),
+
+    #[error("Undefined macro: '{0}'")]
+    UndefinedMacro(String),
+
+    #[error(
+        "Macro '{name}' expects {expected} arguments, but {} provided",
+        match found {
+            Some(n) => format!("{} were", n),
+            None => "none were".to_string(),
+        }
+    )]
+    MacroArity {
+        name: String,
+        expected: usize,
+        /// `None` when the call had no argument list at all
+        found: Option<usize>,
+    },
+
+    #[error("Macro '{0}' is defined both on the command line and in the source")]
+    DuplicateMacro(String),
+
+    #[error("Environment variable '{0}' used in env!() is not set")]
+    UndefinedEnv(String),
+
+    /// Malformed macro definition or invocation
+    #[error("{0}")]
+    MacroSyntax(String),
+
+    #[error("{0}")]
+    Config(String),
+
+    #[error("Build timed out after {0} seconds")]
+    BuildTimeout(u64),
+
+    #[error("Build output is {size} bytes, over the {limit} byte limit of target '{target}'")]
+    TargetLimit {
+        target: String,
+        size: usize,
+        limit: usize,
+    },
+
+    #[error("{0} warning(s) treated as errors (--strict)")]
+    Strict(usize),
+
+    #[error(
+        "Build produced {count} warning(s), over the budget of {budget} (--fail-on-warning-count)"
+    )]
+    WarningBudget { count: usize, budget: usize },
+
+    /// An external program could not be run or failed
+    #[error("{0}")]
+    Command(String),
+
+    #[error("{0}")]
+    Other(String),
+}
+
+impl ErsaError {
+    /// Attach the file a lexical error was found in
+    pub fn lexical(
+        file: &std::path::Path,
+        err: crate::cli::build::preprocess::validate::LexError,
+    ) -> Self {
+        ErsaError::Lexical {
+            file: file.to_path_buf(),
+            line: err.line,
+            column: err.column,
+            message: err.message,
+        }
+    }
+
+    /// Short snake_case name of the variant, for machine-readable output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ErsaError::Io { .. } => "io",
+            ErsaError::Network(_) => "network",
+            ErsaError::Offline(_) => "offline",
+            ErsaError::Parse(_) => "parse",
+            ErsaError::FileNotFound(_) => "file_not_found",
+            ErsaError::ImportNotFound { .. } => "import_not_found",
+            ErsaError::Lexical { .. } => "lexical",
+            ErsaError::CircularImport(_) => "circular_import",
+            ErsaError::TooManyImports(_) => "too_many_imports",
+            ErsaError::Imports(_) => "imports",
+            ErsaError::UndefinedMacro(_) => "undefined_macro",
+            ErsaError::MacroArity { .. } => "macro_arity",
+            ErsaError::DuplicateMacro(_) => "duplicate_macro",
+            ErsaError::MacroSyntax(_) => "macro_syntax",
+            ErsaError::UndefinedEnv(_) => "undefined_env",
+            ErsaError::Config(_) => "config",
+            ErsaError::BuildTimeout(_) => "build_timeout",
+            ErsaError::TargetLimit { .. } => "target_limit",
+            ErsaError::Strict(_) => "strict",
+            ErsaError::WarningBudget { .. } => "warning_budget",
+            ErsaError::Command(_) => "command",
+            ErsaError::Other(_) => "other",
+        }
+    }
+
+    /// The error as a `{kind, message, file, line, col}` object. Location
+    /// fields are null when the error doesn't have one.
+    pub fn to_json(&self) -> serde_json::Value {
+        let (file, line, col) = match self {
+            ErsaError::Lexical {
+                file, line, column, ..
+            } => (Some(file), Some(*line), Some(*column)),
+            ErsaError::FileNotFound(file) | ErsaError::CircularImport(file) => {
+                (Some(file), None, None)
+            }
+            _ => (None, None, None),
+        };
+        serde_json::json!({
+            "kind": self.kind(),
+            "message": self.to_string(),
+            "file": file.map(|f| f.display().to_string()),
+            "line": line,
+            "col": col,
+        })
+    }
+
+    /// Build a `map_err` adapter that wraps an I/O error with `context`
+    pub fn io(context: impl Into<String>) -> impl FnOnce(std::io::Error) -> ErsaError {
+        let context = context.into();
+        move |source| ErsaError::Io { context, source }
+    }
+}
+
+fn describe_tried(tried: &[PathBuf]) -> String {
+    let quoted: Vec<String> = tried.iter().map(|p| format!("'{}'", p.display())).collect();
+    match quoted.as_slice() {
+        [only] => format!("resolved to {}", only),
+        _ => format!("tried {}", quoted.join(", ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_match_previous_format() {
+        let err = ErsaError::io("Failed to read input file")(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "gone",
+        ));
+        assert_eq!(err.to_string(), "Failed to read input file: gone");
+
+        let err = ErsaError::MacroArity {
+            name: "m".to_string(),
+            expected: 2,
+            found: None,
+        };
+        assert_eq!(
+            err.to_string(),
+            "Macro 'm' expects 2 arguments, but none were provided"
+        );
+
+        let err = ErsaError::MacroArity {
+            name: "m".to_string(),
+            expected: 2,
+            found: Some(1),
+        };
+        assert_eq!(
+            err.to_string(),
+            "Macro 'm' expects 2 arguments, but 1 were provided"
+        );
+    }
+
+    #[test]
+    fn test_build_error_as_json() {
+        let err = ErsaError::Lexical {
+            file: PathBuf::from("main.gpc"),
+            line: 3,
+            column: 7,
+            message: "Unterminated string literal".to_string(),
+        };
+        let json: serde_json::Value = serde_json::from_str(&err.to_json().to_string()).unwrap();
+        assert_eq!(json["kind"], "lexical");
+        assert_eq!(json["file"], "main.gpc");
+        assert_eq!(json["line"], 3);
+        assert_eq!(json["col"], 7);
+        assert_eq!(json["message"], err.to_string());
+
+        let json = ErsaError::BuildTimeout(5).to_json();
+        assert_eq!(json["kind"], "build_timeout");
+        assert!(json["file"].is_null());
+    }
+}
@@ -1,3 +1,6 @@
 pub mod cli;
+pub mod config;
+pub mod error;
 pub mod log;
 pub mod network;
+pub mod paths;
@@ -1,57 +1,212 @@
 #![allow(dead_code)]
 
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+static COLOR: AtomicU8 = AtomicU8::new(ColorChoice::Auto as u8);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// When to use ANSI colors in output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Color terminals, unless `NO_COLOR` is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+pub fn set_color(choice: ColorChoice) {
+    COLOR.store(choice as u8, Ordering::Relaxed);
+}
+
+fn color_choice() -> ColorChoice {
+    match COLOR.load(Ordering::Relaxed) {
+        1 => ColorChoice::Always,
+        2 => ColorChoice::Never,
+        _ => ColorChoice::Auto,
+    }
+}
+
+/// Whether output to stdout (or stderr) should be colored
+pub fn use_color(stderr: bool) -> bool {
+    let is_terminal = if stderr {
+        std::io::stderr().is_terminal()
+    } else {
+        std::io::stdout().is_terminal()
+    };
+    should_color(
+        color_choice(),
+        is_terminal,
+        std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()),
+    )
+}
+
+fn should_color(choice: ColorChoice, is_terminal: bool, no_color: bool) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => is_terminal && !no_color,
+    }
+}
+
+/// How warnings and the final error are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum MessageFormat {
+    /// `[TAG] message` lines
+    #[default]
+    Human,
+    /// One JSON object per line on stderr, for editors and other tools
+    Json,
+}
+
+pub fn set_message_format(format: MessageFormat) {
+    JSON.store(format == MessageFormat::Json, Ordering::Relaxed);
+}
+
+fn json_messages() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Format a `[TAG] msg` line, coloring the tag with `style` if enabled
+fn tagged(tag: &str, style: &str, msg: &str, stderr: bool) -> String {
+    if use_color(stderr) {
+        format!("[\x1b[{}m{}\x1b[0m] {}", style, tag, msg)
+    } else {
+        format!("[{}] {}", tag, msg)
+    }
+}
+
+/// Enable or disable the `v*` logging functions
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+/// Number of warnings logged so far in this process
+pub fn warning_count() -> usize {
+    WARNINGS.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+thread_local! {
+    static CAPTURED: std::cell::RefCell<Option<Vec<String>>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Serializes tests that change the global verbosity
+#[cfg(test)]
+pub(crate) static TEST_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Run `f` and return the lines it logged on this thread instead of printing them
+#[cfg(test)]
+pub(crate) fn capture<R>(f: impl FnOnce() -> R) -> (R, Vec<String>) {
+    CAPTURED.with(|c| *c.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let lines = CAPTURED.with(|c| c.borrow_mut().take()).unwrap_or_default();
+    (result, lines)
+}
+
+fn emit(line: String, stderr: bool) {
+    #[cfg(test)]
+    {
+        let captured = CAPTURED.with(|c| {
+            if let Some(lines) = c.borrow_mut().as_mut() {
+                lines.push(line.clone());
+                true
+            } else {
+                false
+            }
+        });
+        if captured {
+            return;
+        }
+    }
+
+    if stderr {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
 // Blue
 #[inline]
 pub fn info(msg: &str) {
-    println!("[\x1b[1;36mINFO\x1b[0m] {}", msg);
+    emit(tagged("INFO", "1;36", msg, false), false);
 }
 
 #[inline]
 pub fn vinfo(msg: &str) {
-    if std::env::var("ERSA_VERBOSE").is_ok() {
-        println!("[\x1b[1;36mINFO\x1b[0m] {}", msg);
+    if is_verbose() {
+        info(msg);
     }
 }
 
 // Yellow
 #[inline]
 pub fn warn(msg: &str) {
-    println!("[\x1b[1;33mWARN\x1b[0m] {}", msg);
+    WARNINGS.fetch_add(1, Ordering::Relaxed);
+    if json_messages() {
+        let json = serde_json::json!({ "kind": "warning", "message": msg });
+        emit(json.to_string(), true);
+    } else {
+        emit(tagged("WARN", "1;33", msg, true), true);
+    }
 }
 
 #[inline]
 pub fn vwarn(msg: &str) {
-    if std::env::var("ERSA_VERBOSE").is_ok() {
-        println!("[\x1b[1;33mWARN\x1b[0m] {}", msg);
+    if is_verbose() {
+        warn(msg);
     }
 }
 
 // Red
 #[inline]
 pub fn error(msg: &str) {
-    eprintln!("[\x1b[1;31mERROR\x1b[0m] {}", msg);
+    emit(tagged("ERROR", "1;31", msg, true), true);
 }
 
 #[inline]
 pub fn verror(msg: &str) {
-    if std::env::var("ERSA_VERBOSE").is_ok() {
-        eprintln!("[\x1b[1;31mERROR\x1b[0m] {}", msg);
+    if is_verbose() {
+        error(msg);
     }
 }
 
 // Green
 #[inline]
 pub fn success(msg: &str) {
-    println!("[\x1b[1;32mSUCCESS\x1b[0m] {}", msg);
+    emit(tagged("SUCCESS", "1;32", msg, false), false);
 }
 
 #[inline]
 pub fn vsuccess(msg: &str) {
-    if std::env::var("ERSA_VERBOSE").is_ok() {
-        println!("[\x1b[1;32mSUCCESS\x1b[0m] {}", msg);
+    if is_verbose() {
+        success(msg);
+    }
+}
+
+/// Report the error a command failed with, in the selected message format
+pub fn report(err: &crate::error::ErsaError) {
+    if json_messages() {
+        emit(err.to_json().to_string(), true);
+    } else {
+        error(&err.to_string());
     }
 }
 
+/// Print a line to stderr with no level tag, for output meant for tools
+#[inline]
+pub fn plain_err(msg: &str) {
+    emit(msg.to_string(), true);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -66,12 +221,70 @@ mod tests {
 
     #[test]
     fn test_verbose_log_functions() {
-        unsafe {
-            std::env::set_var("ERSA_VERBOSE", "1");
-        }
+        let _lock = TEST_LOCK.lock().unwrap();
+
+        set_verbose(true);
         vinfo("This is a verbose info message.");
         vwarn("This is a verbose warning message.");
         verror("This is a verbose error message.");
         vsuccess("This is a verbose success message.");
+        set_verbose(false);
+    }
+
+    #[test]
+    fn test_set_verbose_controls_vinfo() {
+        let _lock = TEST_LOCK.lock().unwrap();
+
+        set_verbose(true);
+        let (_, lines) = capture(|| vinfo("shown"));
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with("shown"));
+
+        set_verbose(false);
+        let (_, lines) = capture(|| vinfo("hidden"));
+        assert!(lines.is_empty());
+    }
+
+    #[test]
+    fn test_color_choice() {
+        assert!(!should_color(ColorChoice::Never, true, false));
+        assert!(should_color(ColorChoice::Always, false, true));
+        assert!(should_color(ColorChoice::Auto, true, false));
+        assert!(!should_color(ColorChoice::Auto, false, false));
+        assert!(!should_color(ColorChoice::Auto, true, true));
+    }
+
+    #[test]
+    fn test_color_flag_controls_escapes() {
+        let _lock = TEST_LOCK.lock().unwrap();
+
+        set_color(ColorChoice::Always);
+        let (_, lines) = capture(|| info("colored"));
+        assert_eq!(lines, ["[\x1b[1;36mINFO\x1b[0m] colored"]);
+
+        set_color(ColorChoice::Never);
+        let (_, lines) = capture(|| warn("plain"));
+        assert_eq!(lines, ["[WARN] plain"]);
+
+        set_color(ColorChoice::Auto);
+    }
+
+    #[test]
+    fn test_json_message_format() {
+        let _lock = TEST_LOCK.lock().unwrap();
+
+        set_message_format(MessageFormat::Json);
+        let err = crate::error::ErsaError::UndefinedMacro("SPEED".to_string());
+        let (_, lines) = capture(|| {
+            warn("unused");
+            report(&err);
+        });
+        set_message_format(MessageFormat::Human);
+
+        let warning: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(warning["kind"], "warning");
+        let error: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(error["kind"], "undefined_macro");
+        assert_eq!(error["message"], "Undefined macro: 'SPEED'");
     }
 }
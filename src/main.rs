@@ -1,32 +1,103 @@
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 pub mod cli;
+pub mod config;
+pub mod error;
 pub mod log;
 pub mod network;
+pub mod paths;
 
 #[derive(Parser)]
-#[command(name = "ersa", version = env!("CARGO_PKG_VERSION"), about = "GPC/GPX Language Tooling")]
+#[command(
+    name = "ersa",
+    about = "GPC/GPX Language Tooling",
+    disable_version_flag = true
+)]
 struct Cli {
     #[arg(long, global = true, help = "Enable verbose output")]
     verbose: bool,
 
+    #[arg(
+        long,
+        global = true,
+        help = "Report what would be installed or replaced without doing it"
+    )]
+    dry_run: bool,
+
+    #[arg(long, global = true, help = "Treat warnings as errors")]
+    strict: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Fail instead of making any network request"
+    )]
+    offline: bool,
+
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = log::ColorChoice::Auto,
+        help = "When to use colors"
+    )]
+    color: log::ColorChoice,
+
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = log::MessageFormat::Human,
+        help = "How to print warnings and errors"
+    )]
+    message_format: log::MessageFormat,
+
+    #[arg(
+        short = 'V',
+        long,
+        help = "Print version (with --verbose, component versions)"
+    )]
+    version: bool,
+
     #[command(subcommand)]
-    command: cli::Command,
+    command: Option<cli::Command>,
 }
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
 
-    if cli.verbose {
-        unsafe {
-            std::env::set_var("ERSA_VERBOSE", "1");
+    log::set_verbose(cli.verbose);
+    log::set_color(cli.color);
+    log::set_message_format(cli.message_format);
+    cli::set_dry_run(cli.dry_run);
+    cli::set_strict(cli.strict);
+    network::set_offline(cli.offline);
+
+    if cli.version {
+        println!("{}", cli::version::version_report(cli.verbose));
+        return;
+    }
+
+    // `config` reads the file itself, so `config set` can fix a broken one
+    if !matches!(cli.command, Some(cli::Command::Config(_))) {
+        match config::Config::load() {
+            Ok(loaded) => config::init(loaded),
+            Err(e) => {
+                log::report(&e);
+                std::process::exit(1);
+            }
         }
     }
 
-    match cli::run(cli.command).await {
+    let Some(command) = cli.command else {
+        let _ = Cli::command().print_help();
+        std::process::exit(2);
+    };
+
+    match cli::run(command).await {
         Ok(_) => (),
         Err(e) => {
-            log::error(&format!("{}", e));
+            log::report(&e);
             std::process::exit(1);
         }
     }
@@ -1,12 +1,165 @@
-use reqwest;
+use crate::error::{ErsaError, Result};
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
 
-pub async fn get_repoinfo(url: &str) -> Result<String, reqwest::Error> {
+/// Responses already fetched during this run, keyed by URL
+static CACHE: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+/// URL of `path` in the REST API of `host`, or of github.com if `host` is
+/// `None`. Other hosts are GitHub Enterprise servers, which serve the API
+/// under `/api/v3`. `host` may include a scheme; it defaults to https.
+pub fn api_url(host: Option<&str>, path: &str) -> String {
+    let host = host.map(|h| h.trim_end_matches('/'));
+    match host {
+        None | Some("github.com" | "https://github.com") => {
+            format!("https://api.github.com/{}", path)
+        }
+        Some(host) if host.contains("://") => format!("{}/api/v3/{}", host, path),
+        Some(host) => format!("https://{}/api/v3/{}", host, path),
+    }
+}
+
+/// API URL of the latest release of `repo` (`owner/name`) on the configured host
+pub fn latest_release_url(repo: &str) -> String {
+    api_url(
+        crate::config::get().github_host().as_deref(),
+        &format!("repos/{}/releases/latest", repo),
+    )
+}
+
+/// GET a GitHub API URL, reusing the response if it was already fetched this run
+pub async fn get_repoinfo(url: &str) -> Result<String> {
+    cached(url, fetch_repoinfo).await
+}
+
+async fn fetch_repoinfo(url: String) -> Result<String> {
     let user_agent = "ersa/1.0";
-    let response = reqwest::Client::new()
-        .get(url)
-        .header("User-Agent", user_agent)
-        .send()
-        .await?;
-    let body = response.text().await?;
+    let mut request = reqwest::Client::new()
+        .get(&url)
+        .header("User-Agent", user_agent);
+    if let Some(token) = crate::config::get().github_token() {
+        request = request.bearer_auth(token);
+    }
+    let network = |e: reqwest::Error| ErsaError::Network(e.to_string());
+    let response = request.send().await.map_err(network)?;
+    let status = response.status();
+    let body = response.text().await.map_err(network)?;
+    check_status(status, body)
+}
+
+/// Pass through the body of a successful response. Any other status is an
+/// error, with the API's `message` if it sent one, so it isn't cached.
+fn check_status(status: StatusCode, body: String) -> Result<String> {
+    if status.is_success() {
+        return Ok(body);
+    }
+
+    let message = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|json| json["message"].as_str().map(str::to_string));
+    Err(ErsaError::Network(match message {
+        Some(message) => format!("GitHub API error ({}): {}", status, message),
+        None => format!("GitHub API error ({})", status),
+    }))
+}
+
+/// Return the cached body for `url`, or fetch and cache it. Failed fetches
+/// aren't cached.
+async fn cached<F, Fut, E>(url: &str, fetch: F) -> std::result::Result<String, E>
+where
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = std::result::Result<String, E>>,
+{
+    let cache = CACHE.get_or_init(Default::default);
+    if let Some(body) = cache.lock().unwrap().get(url) {
+        return Ok(body.clone());
+    }
+
+    let body = fetch(url.to_string()).await?;
+    cache.lock().unwrap().insert(url.to_string(), body.clone());
     Ok(body)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_api_url_hosts() {
+        let path = "repos/zKiwiko/ersa/releases/latest";
+        assert_eq!(
+            api_url(None, path),
+            "https://api.github.com/repos/zKiwiko/ersa/releases/latest"
+        );
+        assert_eq!(api_url(Some("github.com"), path), api_url(None, path));
+        assert_eq!(
+            api_url(Some("github.example.com"), path),
+            "https://github.example.com/api/v3/repos/zKiwiko/ersa/releases/latest"
+        );
+        assert_eq!(
+            api_url(Some("http://ghe.local:8080/"), path),
+            "http://ghe.local:8080/api/v3/repos/zKiwiko/ersa/releases/latest"
+        );
+    }
+
+    #[test]
+    fn test_error_status_is_an_error() {
+        let body = r#"{"message":"API rate limit exceeded"}"#;
+        assert_eq!(
+            check_status(StatusCode::OK, body.to_string()).unwrap(),
+            body
+        );
+
+        let err = check_status(StatusCode::FORBIDDEN, body.to_string()).unwrap_err();
+        assert!(matches!(err, ErsaError::Network(ref msg)
+            if msg == "GitHub API error (403 Forbidden): API rate limit exceeded"));
+
+        let err = check_status(StatusCode::BAD_GATEWAY, "<html>".to_string()).unwrap_err();
+        assert!(matches!(err, ErsaError::Network(ref msg)
+            if msg == "GitHub API error (502 Bad Gateway)"));
+    }
+
+    #[tokio::test]
+    async fn test_repeated_fetch_is_cached() {
+        let calls = AtomicUsize::new(0);
+        let fetch = |url: String| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            async move { Ok::<_, ()>(format!("body of {}", url)) }
+        };
+
+        let url = "https://api.github.com/test/cached";
+        assert_eq!(
+            cached(url, fetch).await.unwrap(),
+            format!("body of {}", url)
+        );
+        assert_eq!(
+            cached(url, fetch).await.unwrap(),
+            format!("body of {}", url)
+        );
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        cached("https://api.github.com/test/other", fetch)
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failed_fetch_is_not_cached() {
+        let url = "https://api.github.com/test/failing";
+        assert!(
+            cached(url, |_| async { Err::<String, _>(()) })
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            cached(url, |_| async { Ok::<_, ()>("ok".to_string()) })
+                .await
+                .unwrap(),
+            "ok"
+        );
+    }
+}
@@ -1,89 +1,114 @@
 pub mod github;
+pub mod version;
+
+use crate::error::{ErsaError, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
 
-pub fn get_ersa_user_dir() -> String {
-    #[cfg(target_os = "windows")]
-    {
-        let appdata = std::env::var("APPDATA")
-            .unwrap_or_else(|_| "C:\\Users\\Default\\AppData\\Roaming".to_string());
-        format!("{}\\ersa", appdata)
-    }
-    #[cfg(not(target_os = "windows"))]
-    {
-        let home = std::env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-        format!("{}/.local/share/ersa", home)
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Make every network request fail immediately instead of being attempted
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline, Ordering::Relaxed);
+}
+
+pub fn is_offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+}
+
+/// Serializes tests that change the offline state or make requests that
+/// check it
+#[cfg(test)]
+pub(crate) static TEST_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Fail with [`ErsaError::Offline`] if `offline`, the state of `--offline`
+fn ensure_online(url: &str, offline: bool) -> Result<()> {
+    if offline {
+        return Err(ErsaError::Offline(url.to_string()));
     }
+    Ok(())
 }
 
-pub async fn get_latest_version(url: &str) -> Result<String, String> {
-    let repo_info = github::get_repoinfo(url).await.map_err(|e| e.to_string())?;
-    let json: serde_json::Value =
-        serde_json::from_str(&repo_info).map_err(|e| format!("Failed to parse JSON: {}", e))?;
+/// Fetch the release JSON from a GitHub releases API URL
+pub async fn get_release(url: &str) -> Result<serde_json::Value> {
+    ensure_online(url, is_offline())?;
+    let repo_info = github::get_repoinfo(url).await?;
+    let json: serde_json::Value = serde_json::from_str(&repo_info)
+        .map_err(|e| ErsaError::Parse(format!("Failed to parse JSON: {}", e)))?;
 
     // Check if the API returned an error
     if let Some(message) = json.get("message") {
-        return Err(format!(
+        return Err(ErsaError::Network(format!(
             "GitHub API error: {}",
             message.as_str().unwrap_or("Unknown error")
-        ));
+        )));
     }
 
-    let tag_name = json["tag_name"]
+    Ok(json)
+}
+
+/// Get the tag name of a release
+pub fn release_tag(release: &serde_json::Value) -> Result<String> {
+    let tag_name = release["tag_name"]
         .as_str()
-        .ok_or("No tag_name field in response")?
+        .ok_or_else(|| ErsaError::Parse("No tag_name field in response".to_string()))?
         .to_string();
     Ok(tag_name)
 }
 
-pub async fn download_latest_release(url: &str) -> Result<(), String> {
-    let repo_info = github::get_repoinfo(url).await.map_err(|e| e.to_string())?;
-    let json: serde_json::Value =
-        serde_json::from_str(&repo_info).map_err(|e| format!("Failed to parse JSON: {}", e))?;
-
-    // Check if the API returned an error
-    if let Some(message) = json.get("message") {
-        return Err(format!(
-            "GitHub API error: {}",
-            message.as_str().unwrap_or("Unknown error")
-        ));
-    }
-
-    let assets = json["assets"]
+/// Find the download URL of the asset named `asset_name` in a release
+pub fn find_asset_url<'a>(release: &'a serde_json::Value, asset_name: &str) -> Result<&'a str> {
+    let assets = release["assets"]
         .as_array()
-        .ok_or("No assets field in response")?;
-
-    #[cfg(target_os = "windows")]
-    let asset_name = "ersa_lsp.exe";
-
-    #[cfg(not(target_os = "windows"))]
-    let asset_name = "ersa_lsp";
+        .ok_or_else(|| ErsaError::Parse("No assets field in response".to_string()))?;
 
     let asset = assets
         .iter()
         .find(|a| a["name"].as_str() == Some(asset_name))
-        .ok_or(format!("Asset '{}' not found in release", asset_name))?;
+        .ok_or_else(|| {
+            ErsaError::Network(format!("Asset '{}' not found in release", asset_name))
+        })?;
 
     let download_url = asset["browser_download_url"]
         .as_str()
-        .ok_or("No download URL found")?;
+        .ok_or_else(|| ErsaError::Parse("No download URL found".to_string()))?;
+    Ok(download_url)
+}
 
+pub async fn download_bytes(url: &str) -> Result<Vec<u8>> {
+    ensure_online(url, is_offline())?;
     let response = reqwest::Client::new()
-        .get(download_url)
+        .get(url)
         .send()
         .await
-        .map_err(|e| format!("Failed to download: {}", e))?;
+        .map_err(|e| ErsaError::Network(format!("Failed to download: {}", e)))?
+        .error_for_status()
+        .map_err(|e| ErsaError::Network(format!("Failed to download: {}", e)))?;
     let bytes = response
         .bytes()
         .await
-        .map_err(|e| format!("Failed to read bytes: {}", e))?;
+        .map_err(|e| ErsaError::Network(format!("Failed to read bytes: {}", e)))?;
+    Ok(bytes.to_vec())
+}
+
+pub async fn get_latest_version(url: &str) -> Result<String> {
+    let release = get_release(url).await?;
+    release_tag(&release)
+}
+
+pub async fn download_latest_release(url: &str) -> Result<()> {
+    let release = get_release(url).await?;
 
-    let user_dir = get_ersa_user_dir();
-    std::fs::create_dir_all(&user_dir).map_err(|e| format!("Failed to create directory: {}", e))?;
+    let download_url = find_asset_url(&release, crate::paths::LSP_BINARY_NAME)?;
+    let bytes = download_bytes(download_url).await?;
 
-    let file_path = format!("{}/{}", user_dir, asset_name);
-    std::fs::write(&file_path, bytes).map_err(|e| format!("Failed to write file: {}", e))?;
+    let user_dir = crate::paths::user_dir();
+    std::fs::create_dir_all(&user_dir).map_err(ErsaError::io("Failed to create directory"))?;
+
+    let file_path = crate::paths::lsp_path();
+    std::fs::write(&file_path, bytes).map_err(ErsaError::io("Failed to write file"))?;
 
     #[cfg(not(target_os = "windows"))]
     {
@@ -94,3 +119,100 @@ pub async fn download_latest_release(url: &str) -> Result<(), String> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Holds `--offline` on for the life of the guard, and turns it off
+    /// again even if the test panics. Holds [`TEST_LOCK`] too, so no other
+    /// test sees the offline state change under it.
+    struct OfflineGuard {
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl OfflineGuard {
+        fn new() -> Self {
+            let lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            set_offline(true);
+            OfflineGuard { _lock: lock }
+        }
+    }
+
+    impl Drop for OfflineGuard {
+        fn drop(&mut self) {
+            set_offline(false);
+        }
+    }
+
+    #[test]
+    fn test_ensure_online() {
+        let url = "https://api.github.com/repos/example/example/releases/latest";
+        assert!(ensure_online(url, false).is_ok());
+        assert!(matches!(
+            ensure_online(url, true),
+            Err(ErsaError::Offline(ref u)) if u == url
+        ));
+    }
+
+    /// Serve one request on a local port with `response`, returning its URL
+    fn serve_once(response: &'static str) -> String {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/asset.tar.xz", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn test_download_error_status() {
+        let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let url = serve_once(
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\nConnection: close\r\n\r\nNot Found",
+        );
+
+        let err = runtime.block_on(download_bytes(&url)).unwrap_err();
+        assert!(matches!(err, ErsaError::Network(ref msg) if msg.contains("404")));
+    }
+
+    #[tokio::test]
+    async fn test_offline_fails_fast() {
+        let _offline = OfflineGuard::new();
+        let url = "https://api.github.com/repos/example/example/releases/latest";
+
+        assert!(matches!(get_release(url).await, Err(ErsaError::Offline(_))));
+        assert!(matches!(
+            download_bytes(url).await,
+            Err(ErsaError::Offline(_))
+        ));
+        assert!(matches!(
+            get_latest_version(url).await,
+            Err(ErsaError::Offline(_))
+        ));
+        assert!(matches!(
+            download_latest_release(url).await,
+            Err(ErsaError::Offline(_))
+        ));
+
+        let commands = [
+            crate::cli::lsp::update::check_update().await.map(|_| ()),
+            crate::cli::self_update::run(crate::cli::self_update::SelfArgs {
+                command: crate::cli::self_update::SelfCommand::Update,
+            })
+            .await,
+        ];
+        for result in commands {
+            assert!(result.unwrap_err().to_string().contains("--offline"));
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use crate::error::{ErsaError, Result};
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = s.trim().trim_start_matches('v');
+        let parts: Vec<&str> = s.split('.').collect();
+
+        if parts.len() != 3 {
+            return Err(ErsaError::Parse(format!("Invalid version format: {}", s)));
+        }
+
+        let major = parts[0]
+            .parse()
+            .map_err(|_| ErsaError::Parse(format!("Invalid major version: {}", parts[0])))?;
+        let minor = parts[1]
+            .parse()
+            .map_err(|_| ErsaError::Parse(format!("Invalid minor version: {}", parts[1])))?;
+        let patch = parts[2]
+            .parse()
+            .map_err(|_| ErsaError::Parse(format!("Invalid patch version: {}", parts[2])))?;
+
+        Ok(Version {
+            major,
+            minor,
+            patch,
+        })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(
+            Version::parse("v1.2.3").unwrap(),
+            Version {
+                major: 1,
+                minor: 2,
+                patch: 3
+            }
+        );
+        assert!(Version::parse("1.2").is_err());
+        assert!(Version::parse("1.x.3").is_err());
+    }
+
+    #[test]
+    fn test_version_ordering() {
+        assert!(Version::parse("0.2.0").unwrap() > Version::parse("0.1.9").unwrap());
+        assert!(Version::parse("1.0.0").unwrap() > Version::parse("0.10.10").unwrap());
+    }
+}
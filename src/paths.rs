@@ -0,0 +1,123 @@
+use std::path::PathBuf;
+
+#[cfg(target_os = "windows")]
+pub const LSP_BINARY_NAME: &str = "ersa_lsp.exe";
+#[cfg(not(target_os = "windows"))]
+pub const LSP_BINARY_NAME: &str = "ersa_lsp";
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+fn user_dir_for(windows: bool, env: impl Fn(&str) -> Option<String>) -> PathBuf {
+    if windows {
+        PathBuf::from(
+            env("APPDATA").unwrap_or_else(|| String::from("C:\\Users\\Default\\AppData\\Roaming")),
+        )
+        .join("ersa")
+    } else {
+        PathBuf::from(env("HOME").unwrap_or_else(|| String::from("/tmp"))).join(".local/share/ersa")
+    }
+}
+
+fn config_dir_for(windows: bool, env: impl Fn(&str) -> Option<String>) -> PathBuf {
+    if windows {
+        PathBuf::from(
+            env("APPDATA").unwrap_or_else(|| String::from("C:\\Users\\Default\\AppData\\Roaming")),
+        )
+        .join("ersa")
+    } else if let Some(xdg) = env("XDG_CONFIG_HOME") {
+        PathBuf::from(xdg).join("ersa")
+    } else {
+        PathBuf::from(env("HOME").unwrap_or_else(|| String::from("/tmp"))).join(".config/ersa")
+    }
+}
+
+/// Directory holding ersa's installed data, such as the LSP binary
+pub fn user_dir() -> PathBuf {
+    user_dir_for(cfg!(target_os = "windows"), env)
+}
+
+/// Path where the LSP binary is installed
+pub fn lsp_path() -> PathBuf {
+    user_dir().join(LSP_BINARY_NAME)
+}
+
+/// Path of the global config file
+pub fn config_path() -> PathBuf {
+    config_dir_for(cfg!(target_os = "windows"), env).join("config.toml")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock_env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: Vec<(String, String)> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone())
+    }
+
+    #[test]
+    fn test_unix_layout() {
+        let env = mock_env(&[("HOME", "/home/kiwi")]);
+        assert_eq!(
+            user_dir_for(false, &env),
+            PathBuf::from("/home/kiwi/.local/share/ersa")
+        );
+        assert_eq!(
+            config_dir_for(false, &env),
+            PathBuf::from("/home/kiwi/.config/ersa")
+        );
+
+        let env = mock_env(&[("HOME", "/home/kiwi"), ("XDG_CONFIG_HOME", "/xdg")]);
+        assert_eq!(config_dir_for(false, &env), PathBuf::from("/xdg/ersa"));
+
+        assert_eq!(
+            user_dir_for(false, mock_env(&[])),
+            PathBuf::from("/tmp/.local/share/ersa")
+        );
+    }
+
+    #[test]
+    fn test_windows_layout() {
+        let env = mock_env(&[("APPDATA", "C:\\Users\\kiwi\\AppData\\Roaming")]);
+        let expected = PathBuf::from("C:\\Users\\kiwi\\AppData\\Roaming").join("ersa");
+        assert_eq!(user_dir_for(true, &env), expected);
+        assert_eq!(config_dir_for(true, &env), expected);
+
+        // HOME and XDG are ignored on Windows
+        let env = mock_env(&[("HOME", "/home/kiwi"), ("XDG_CONFIG_HOME", "/xdg")]);
+        assert_eq!(
+            user_dir_for(true, &env),
+            PathBuf::from("C:\\Users\\Default\\AppData\\Roaming").join("ersa")
+        );
+    }
+
+    #[test]
+    fn test_lsp_path_is_in_user_dir() {
+        let lsp = lsp_path();
+        assert_eq!(lsp.parent().unwrap(), user_dir());
+        assert_eq!(lsp.file_name().unwrap(), LSP_BINARY_NAME);
+    }
+
+    #[test]
+    fn test_user_dir_follows_runtime_home() {
+        // The lookup is consulted on every call, so a changed HOME is seen
+        let home = std::cell::Cell::new("/home/ersa-test-a");
+        let env = |name: &str| (name == "HOME").then(|| home.get().to_string());
+
+        assert_eq!(
+            user_dir_for(false, env),
+            PathBuf::from("/home/ersa-test-a/.local/share/ersa")
+        );
+
+        home.set("/home/ersa-test-b");
+        assert_eq!(
+            user_dir_for(false, env),
+            PathBuf::from("/home/ersa-test-b/.local/share/ersa")
+        );
+    }
+}